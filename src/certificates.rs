@@ -0,0 +1,153 @@
+use crate::error::SimpletError;
+use crate::proxy::TlsConfig;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+
+/// Name of the Traefik certificate resolver obtaining certificates over ACME
+pub const ACME_RESOLVER: &str = "acme";
+/// File Traefik stores its ACME account and certificates in
+pub const ACME_STORAGE: &str = "acme.json";
+/// A certificate expiring sooner than this should have been renewed already
+///
+/// Traefik renews ACME certificates 30 days before they expire.
+pub const RENEWAL_OVERDUE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The certificate served for the host name of an instance
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CertificateInfo {
+    pub hostname: String,
+    /// Seconds since the unix epoch after which the certificate is no longer valid
+    pub not_after: i64,
+}
+
+impl CertificateInfo {
+    /// Whether the certificate expires within [`RENEWAL_OVERDUE`] of `now`
+    pub fn renewal_overdue(&self, now: i64) -> bool {
+        self.not_after - now < RENEWAL_OVERDUE.as_secs() as i64
+    }
+}
+
+/// The certificate the proxy serves for `hostname`, `None` if there is none yet
+///
+/// `acme_dir` is the directory Traefik keeps [`ACME_STORAGE`] in.
+pub fn find_certificate(
+    tls: &TlsConfig,
+    acme_dir: &Path,
+    hostname: &str,
+) -> Result<Option<CertificateInfo>, SimpletError> {
+    let pem = match tls {
+        TlsConfig::Files { cert_dir } => read_if_exists(&cert_dir.join(format!("{hostname}.crt")))?,
+        TlsConfig::Wildcard { cert_file, .. } => read_if_exists(cert_file)?,
+        TlsConfig::Acme { .. } => match read_if_exists(&acme_dir.join(ACME_STORAGE))? {
+            Some(storage) => acme_certificate(&storage, hostname)?,
+            None => None,
+        },
+    };
+
+    pem.map(|pem| {
+        Ok(CertificateInfo {
+            hostname: hostname.to_string(),
+            not_after: not_after(&pem)?,
+        })
+    })
+    .transpose()
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>, SimpletError> {
+    match std::fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(SimpletError::Certificate(format!(
+            "{}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+/// The PEM chain Traefik obtained for `hostname`, from its ACME storage file
+fn acme_certificate(storage: &[u8], hostname: &str) -> Result<Option<Vec<u8>>, SimpletError> {
+    let invalid =
+        |reason: String| SimpletError::Certificate(format!("{}: {}", ACME_STORAGE, reason));
+
+    let storage: serde_json::Value =
+        serde_json::from_slice(storage).map_err(|e| invalid(e.to_string()))?;
+    let certificates = storage[ACME_RESOLVER]["Certificates"].as_array();
+    let Some(certificate) = certificates
+        .into_iter()
+        .flatten()
+        .find(|certificate| certificate["domain"]["main"] == hostname)
+    else {
+        return Ok(None);
+    };
+
+    let encoded = certificate["certificate"]
+        .as_str()
+        .ok_or_else(|| invalid(format!("no certificate for {}", hostname)))?;
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map(Some)
+        .map_err(|e| invalid(e.to_string()))
+}
+
+/// Expiry of the first certificate of a PEM chain, in seconds since the unix epoch
+fn not_after(pem: &[u8]) -> Result<i64, SimpletError> {
+    let invalid = |reason: String| SimpletError::Certificate(reason);
+
+    let (_, pem) = x509_parser::pem::parse_x509_pem(pem).map_err(|e| invalid(e.to_string()))?;
+    let certificate = pem.parse_x509().map_err(|e| invalid(e.to_string()))?;
+    Ok(certificate.validity().not_after.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-signed certificate for poa.example.com, valid until 2126-09-23
+    const CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----
+MIIBijCCATGgAwIBAgIUBXEhkX7RZlSY/X7716tYnTL7qYQwCgYIKoZIzj0EAwIw
+GjEYMBYGA1UEAwwPcG9hLmV4YW1wbGUuY29tMCAXDTI2MTAxNzIwMzgyNloYDzIx
+MjYwOTIzMjAzODI2WjAaMRgwFgYDVQQDDA9wb2EuZXhhbXBsZS5jb20wWTATBgcq
+hkjOPQIBBggqhkjOPQMBBwNCAAScBLHfnrSWqPDGBj39KJ4A3dKN2PUEkM5q5YAW
+puGyJ642thPLLm1o5RUPghjNUXdJpNTH4Wsx4UHlN4QWUyFKo1MwUTAdBgNVHQ4E
+FgQUvQIjM7lKKcWc0U+vt68dIftasm0wHwYDVR0jBBgwFoAUvQIjM7lKKcWc0U+v
+t68dIftasm0wDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNHADBEAiAkf6xP
+d5TrSykesP1WIDwTo4K/HJp2Y31clEO+AND5SAIgPdF7arpK0ARx7iMC06VdW4Qg
+LKMKlMGYUgJZXc6FEig=
+-----END CERTIFICATE-----
+";
+    const NOT_AFTER: i64 = 4945869506;
+
+    #[test]
+    fn test_find_acme_certificate() {
+        let dir = std::env::temp_dir().join(format!("simplets-acme-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let storage = serde_json::json!({
+            ACME_RESOLVER: {
+                "Certificates": [{
+                    "domain": { "main": "poa.example.com" },
+                    "certificate": base64::engine::general_purpose::STANDARD.encode(CERTIFICATE),
+                    "key": "",
+                }]
+            }
+        });
+        std::fs::write(dir.join(ACME_STORAGE), storage.to_string()).unwrap();
+
+        let tls = TlsConfig::Acme {
+            email: "ops@example.com".to_string(),
+            ca_server: None,
+            ca_certificates: None,
+        };
+        let found = find_certificate(&tls, &dir, "poa.example.com");
+        let missing = find_certificate(&tls, &dir, "airdrop.example.com");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let found = found.unwrap().unwrap();
+        assert_eq!(found.not_after, NOT_AFTER);
+        assert!(!found.renewal_overdue(NOT_AFTER - 30 * 24 * 60 * 60));
+        assert!(found.renewal_overdue(NOT_AFTER - 24 * 60 * 60));
+        assert!(missing.unwrap().is_none());
+    }
+}
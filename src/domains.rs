@@ -0,0 +1,124 @@
+use crate::error::SimpletError;
+use gadget_sdk::subxt_core::ext::sp_core::bytes::to_hex;
+use gadget_sdk::subxt_core::ext::sp_core::keccak_256;
+use hickory_resolver::TokioAsyncResolver;
+use serde::{Deserialize, Serialize};
+
+/// Label prepended to a custom domain to name the TXT record proving ownership of it
+pub const CHALLENGE_LABEL: &str = "_simplets-challenge";
+
+/// TXT record a customer publishes to prove they control a custom domain
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DomainChallenge {
+    pub domain: String,
+    /// Name of the TXT record, `_simplets-challenge.<domain>`
+    pub record: String,
+    /// Value the TXT record must hold
+    pub token: String,
+}
+
+impl DomainChallenge {
+    /// The challenge for routing `domain` to `instance_id`
+    ///
+    /// The token is derived from both, so a record published for one instance does not
+    /// verify the domain for another.
+    pub fn new(instance_id: &str, domain: &str) -> Self {
+        let hash = keccak_256(format!("{}/{}", instance_id, domain).as_bytes());
+        let token = to_hex(&hash[..16], false)
+            .trim_start_matches("0x")
+            .to_string();
+
+        Self {
+            domain: domain.to_string(),
+            record: format!("{}.{}", CHALLENGE_LABEL, domain),
+            token,
+        }
+    }
+
+    /// Look the TXT record up and check it holds the token
+    pub async fn verify(&self) -> Result<(), SimpletError> {
+        let failed = |reason: String| SimpletError::DomainVerification {
+            domain: self.domain.clone(),
+            reason,
+        };
+
+        let resolver =
+            TokioAsyncResolver::tokio_from_system_conf().map_err(|e| failed(e.to_string()))?;
+        // A trailing dot keeps the resolver from appending the host's search domains
+        let lookup = resolver
+            .txt_lookup(format!("{}.", self.record))
+            .await
+            .map_err(|e| failed(format!("looking up {}: {}", self.record, e)))?;
+
+        if lookup.iter().any(|txt| txt.to_string() == self.token) {
+            Ok(())
+        } else {
+            Err(failed(format!(
+                "{} does not hold the token {}",
+                self.record, self.token
+            )))
+        }
+    }
+}
+
+/// Normalize a custom domain, rejecting anything that is not a plain host name
+pub fn parse_domain(domain: &str) -> Result<String, SimpletError> {
+    let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    let invalid = |reason: &str| {
+        SimpletError::InvalidConfig(format!("custom_domain `{}` {}", domain, reason))
+    };
+
+    if domain.len() > 253 {
+        return Err(invalid("is too long"));
+    }
+    let labels = domain.split('.').collect::<Vec<_>>();
+    if labels.len() < 2 {
+        return Err(invalid("is not a fully qualified host name"));
+    }
+    for label in labels {
+        let valid = !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !valid {
+            return Err(invalid("is not a valid host name"));
+        }
+    }
+
+    Ok(domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_domain() {
+        assert_eq!(parse_domain("POAP.MyConf.xyz.").unwrap(), "poap.myconf.xyz");
+        for invalid in [
+            "localhost",
+            "poap..xyz",
+            "-poap.xyz",
+            "poap.xyz/path",
+            "*.xyz",
+        ] {
+            assert!(matches!(
+                parse_domain(invalid),
+                Err(SimpletError::InvalidConfig(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_challenge_is_bound_to_instance() {
+        let challenge = DomainChallenge::new("poa-1", "poap.myconf.xyz");
+        assert_eq!(challenge.record, "_simplets-challenge.poap.myconf.xyz");
+        assert_eq!(challenge.token.len(), 32);
+        assert_eq!(challenge, DomainChallenge::new("poa-1", "poap.myconf.xyz"));
+        assert_ne!(
+            challenge.token,
+            DomainChallenge::new("poa-2", "poap.myconf.xyz").token
+        );
+    }
+}
@@ -0,0 +1,45 @@
+use gadget_sdk::docker::bollard;
+use std::time::Duration;
+
+/// Errors that can occur while deploying or managing a simplet
+#[derive(Debug, thiserror::Error)]
+pub enum SimpletError {
+    #[error("Invalid simplet config: {0}")]
+    InvalidConfig(String),
+    #[error("Operator has no configuration for the {0} simplet")]
+    MissingOperatorConfig(String),
+    #[error("Invalid operator config {0}")]
+    OperatorConfig(String),
+    #[error("Simplet instance {0} not found")]
+    InstanceNotFound(String),
+    #[error("No caller recorded for job call {0}")]
+    UnknownCaller(u64),
+    #[error("{caller} is not allowed to manage simplet instance {instance_id}")]
+    Unauthorized { caller: String, instance_id: String },
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Docker error: {0}")]
+    Docker(#[from] bollard::errors::Error),
+    #[error("{what} did not become ready within {waited:?}")]
+    Timeout { what: String, waited: Duration },
+    #[error("Container {0} exited before becoming ready")]
+    ContainerExited(String),
+    #[error("Failed to pull image {image}: {reason}")]
+    ImagePull { image: String, reason: String },
+    #[error("Container {0} failed its health check")]
+    Unhealthy(String),
+    #[error("Simplet instance {0} is already deployed with a different config")]
+    ConfigConflict(String),
+    #[error("Simplet instance {0} is busy with another job, try again later")]
+    Busy(String),
+    #[error("Simplet instance {instance_id} cannot be recovered: {reason}")]
+    Unrecoverable { instance_id: String, reason: String },
+    #[error("Failed to configure the reverse proxy: {0}")]
+    Proxy(String),
+    #[error("Failed to verify ownership of {domain}: {reason}")]
+    DomainVerification { domain: String, reason: String },
+    #[error("Invalid certificate: {0}")]
+    Certificate(String),
+    #[error("Failed to update the instance registry: {0}")]
+    Registry(#[from] std::io::Error),
+}
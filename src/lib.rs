@@ -1,22 +1,265 @@
+use gadget_sdk::docker::connect_to_docker;
 use gadget_sdk::tangle_subxt::tangle_testnet_runtime::api;
-use gadget_sdk::{self as sdk, error};
-use simplets::email_airdrop::EmailAirdropBuilder;
+use gadget_sdk::{self as sdk, info, warn};
+use serde::{Deserialize, Serialize};
+use simplets::email_airdrop::{EmailAirdropBuilder, EmailAirdropConfig};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::{collections::HashMap, convert::Infallible};
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 
 use api::services::events::JobCalled;
-use sdk::event_listener::tangle::{jobs::services_pre_processor, TangleEventListener};
+use sdk::event_listener::tangle::{
+    jobs::services_pre_processor, AccountId32, BlockNumber, TangleEvent, TangleEventListener,
+};
 
+pub mod certificates;
+pub mod domains;
+pub mod error;
+pub mod operator_config;
+pub mod ports;
+pub mod proxy;
+pub mod reconcile;
+pub mod registry;
 pub mod simplets;
-use simplets::proof_of_attendance::ProofOfAttendanceBuilder;
-use simplets::{CommonConfig, SimpletsBuilder};
+use domains::DomainChallenge;
+use error::SimpletError;
+use operator_config::OperatorConfig;
+use ports::PortAllocator;
+use registry::{InstanceRecord, InstanceRegistry};
+use simplets::proof_of_attendance::{ProofOfAttendanceBuilder, ProofOfAttendanceConfig};
+use simplets::status::InstancePhase;
+use simplets::{
+    ApillonSimpletsDocker, CommonConfig, InstanceIdentity, ServiceConfig, ServiceType,
+    SimpletsBuilder, VolumePolicy,
+};
 
 #[derive(Clone)]
 pub struct SimpletsContext {
+    pub operator_config: Arc<OperatorConfig>,
     pub simplet_configs: HashMap<String, CommonConfig>,
     pub config: sdk::config::StdGadgetConfiguration,
     pub running_services: Arc<RwLock<HashMap<String, simplets::ApillonSimpletsDocker>>>,
+    pub registry: Arc<RwLock<InstanceRegistry>>,
+    /// Origins of pending job calls, keyed by call id
+    pub origins: Arc<RwLock<HashMap<u64, JobOrigin>>>,
+    /// Host ports apps are published on
+    pub ports: Arc<Mutex<PortAllocator>>,
+}
+
+/// Who called a job and when, as seen in its `JobCalled` event
+#[derive(Clone, Debug)]
+pub struct JobOrigin {
+    pub call_id: u64,
+    pub caller: AccountId32,
+    pub block_number: BlockNumber,
+}
+
+/// [`services_pre_processor`] that also records the [`JobOrigin`] of the call
+///
+/// Jobs only receive their params, so they look the origin up with
+/// [`SimpletsContext::take_origin`] using their active call id.
+pub async fn simplets_pre_processor(
+    event: TangleEvent<SimpletsContext, JobCalled>,
+) -> Result<TangleEvent<SimpletsContext, JobCalled>, sdk::Error> {
+    let event = services_pre_processor(event).await?;
+    if let Some(call_id) = event.call_id {
+        let origin = JobOrigin {
+            call_id,
+            caller: event.evt.caller.clone(),
+            block_number: event.block_number,
+        };
+        event.context.origins.write().await.insert(call_id, origin);
+    }
+    Ok(event)
+}
+
+/// Directory the operator keeps its state in
+pub fn data_dir(config: &sdk::config::StdGadgetConfiguration) -> PathBuf {
+    config
+        .data_dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("./data"))
+}
+
+impl SimpletsContext {
+    /// Id of the service instance of this blueprint the operator runs
+    pub fn service_id(&self) -> u64 {
+        self.config.service_id().unwrap_or_default()
+    }
+
+    /// Directory the reverse proxy keeps its configuration and ACME storage in
+    pub fn proxy_dir(&self) -> PathBuf {
+        data_dir(&self.config).join("proxy")
+    }
+
+    /// Record the certificates currently served for every instance behind TLS
+    ///
+    /// Certificates are renewed by the proxy or the operator, this only tracks their
+    /// expiry and warns when a renewal is overdue.
+    pub async fn refresh_certificates(&self) {
+        let Some(tls) = &self.operator_config.proxy.tls else {
+            return;
+        };
+        let acme_dir = self.proxy_dir().join("acme");
+        let now = chrono::Utc::now().timestamp();
+
+        let mut registry = self.registry.write().await;
+        let records = registry.instances().cloned().collect::<Vec<_>>();
+        for mut record in records {
+            let hostnames = record
+                .endpoint
+                .iter()
+                .flat_map(|e| e.hostname.iter().chain(&e.custom_domain))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            let mut found = Vec::new();
+            for hostname in hostnames {
+                match certificates::find_certificate(tls, &acme_dir, &hostname) {
+                    Ok(Some(certificate)) => {
+                        if certificate.renewal_overdue(now) {
+                            warn!(
+                                "Certificate for {} of simplet {} expires at {} and was not renewed",
+                                hostname, record.instance_id, certificate.not_after
+                            );
+                        }
+                        found.push(certificate);
+                    }
+                    Ok(None) => warn!(
+                        "No certificate for {} of simplet {} yet",
+                        hostname, record.instance_id
+                    ),
+                    Err(e) => warn!("Failed to read the certificate for {}: {}", hostname, e),
+                }
+            }
+
+            if record.certificates == found {
+                continue;
+            }
+            record.certificates = found;
+            let instance_id = record.instance_id.clone();
+            if let Err(e) = registry.upsert(record) {
+                sdk::error!(
+                    "Failed to persist the certificates of simplet {}: {}",
+                    instance_id,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Take the origin [`simplets_pre_processor`] recorded for `call_id`
+    pub async fn take_origin(&self, call_id: u64) -> Result<JobOrigin, SimpletError> {
+        self.origins
+            .write()
+            .await
+            .remove(&call_id)
+            .ok_or(SimpletError::UnknownCaller(call_id))
+    }
+
+    /// Ensure the caller of a job owns `instance_id` or is an operator admin
+    pub async fn authorize(
+        &self,
+        instance_id: &str,
+        origin: &JobOrigin,
+    ) -> Result<(), SimpletError> {
+        let registry = self.registry.read().await;
+        let record = registry
+            .get(instance_id)
+            .ok_or_else(|| SimpletError::InstanceNotFound(instance_id.to_string()))?;
+
+        if record.is_managed_by(&origin.caller, &self.operator_config.admins) {
+            Ok(())
+        } else {
+            let caller = origin.caller.to_string();
+            warn!("Rejected call from {} on simplet {}", caller, instance_id);
+            Err(SimpletError::Unauthorized {
+                caller,
+                instance_id: instance_id.to_string(),
+            })
+        }
+    }
+
+    /// Start tracking a deployed instance, both in memory and in the persistent registry
+    pub async fn track(
+        &self,
+        service: ApillonSimpletsDocker,
+        config_hash: String,
+        origin: &JobOrigin,
+    ) {
+        let mut record = InstanceRecord::new(&service, config_hash);
+        record.owner = Some(origin.caller.clone());
+        record.created_at_block = Some(origin.block_number);
+        if let Err(e) = self.registry.write().await.upsert(record) {
+            sdk::error!(
+                "Failed to persist simplet {} to the registry: {}",
+                service.instance_id(),
+                e
+            );
+        }
+
+        self.running_services
+            .write()
+            .await
+            .insert(service.instance_id().to_string(), service);
+    }
+
+    /// Persist the env vars, images and app container of an instance after it changed
+    pub async fn record_update(&self, service: &ApillonSimpletsDocker) {
+        let mut registry = self.registry.write().await;
+        let Some(mut record) = registry.get(service.instance_id()).cloned() else {
+            return;
+        };
+        record.env_vars = service.env_vars().clone();
+        record.app_container_id = service.app_container_id().map(str::to_string);
+        record.images = Some(service.images().clone());
+        record.config_hash = simplets::env_fingerprint(service.env_vars());
+        record.endpoint = service.endpoint().cloned();
+        if let Err(e) = registry.upsert(record) {
+            sdk::error!(
+                "Failed to persist update of simplet {} to the registry: {}",
+                service.instance_id(),
+                e
+            );
+        }
+    }
+
+    /// Record the custom domain challenge the owner of `instance_id` has yet to pass
+    pub async fn set_pending_domain(
+        &self,
+        instance_id: &str,
+        challenge: Option<DomainChallenge>,
+    ) -> Result<(), SimpletError> {
+        let mut registry = self.registry.write().await;
+        let mut record = registry
+            .get(instance_id)
+            .cloned()
+            .ok_or_else(|| SimpletError::InstanceNotFound(instance_id.to_string()))?;
+        record.pending_domain = challenge;
+        registry.upsert(record)?;
+        Ok(())
+    }
+
+    /// Stop tracking an instance, returning its handle if it was still known
+    pub async fn untrack(&self, instance_id: &str) -> Option<ApillonSimpletsDocker> {
+        match self.registry.write().await.remove(instance_id) {
+            Ok(record) => {
+                let endpoint = record.and_then(|record| record.endpoint);
+                if let Some(host_port) = endpoint.and_then(|endpoint| endpoint.host_port) {
+                    self.ports.lock().await.release(host_port);
+                }
+            }
+            Err(e) => sdk::error!(
+                "Failed to remove simplet {} from the registry: {}",
+                instance_id,
+                e
+            ),
+        }
+
+        self.running_services.write().await.remove(instance_id)
+    }
 }
 
 #[sdk::job(
@@ -25,183 +268,687 @@ pub struct SimpletsContext {
     result(_),
     event_listener(
         listener = TangleEventListener::<SimpletsContext, JobCalled>,
-        pre_processor = services_pre_processor,
+        pre_processor = simplets_pre_processor,
     ),
 )]
 pub async fn run_proof_of_attendance_simplet(
     custom_config: Vec<u8>,
     context: SimpletsContext,
-) -> Result<String, Infallible> {
+) -> Result<String, SimpletError> {
+    let call_id = RUN_PROOF_OF_ATTENDANCE_SIMPLET_ACTIVE_CALL_ID.load(Ordering::Relaxed);
+    let origin = context.take_origin(call_id).await?;
+    deploy_simplet::<ProofOfAttendanceBuilder>(&custom_config, &origin, &context).await
+}
+
+#[sdk::job(
+    id = 1,
+    params(custom_config),
+    result(_),
+    event_listener(
+        listener = TangleEventListener::<SimpletsContext, JobCalled>,
+        pre_processor = simplets_pre_processor,
+    ),
+)]
+pub async fn run_email_airdrop_simplet(
+    custom_config: Vec<u8>,
+    context: SimpletsContext,
+) -> Result<String, SimpletError> {
+    let call_id = RUN_EMAIL_AIRDROP_SIMPLET_ACTIVE_CALL_ID.load(Ordering::Relaxed);
+    let origin = context.take_origin(call_id).await?;
+    deploy_simplet::<EmailAirdropBuilder>(&custom_config, &origin, &context).await
+}
+
+/// Deploy a simplet from a caller's JSON config, merged with the operator's defaults
+///
+/// Adding a new simplet only requires a [`SimpletsBuilder`] impl and a job calling this.
+pub async fn deploy_simplet<B: SimpletsBuilder + Send>(
+    custom_config: &[u8],
+    origin: &JobOrigin,
+    context: &SimpletsContext,
+) -> Result<String, SimpletError> {
     // Extract configuration values from context
-    let config = context.simplet_configs.get("proof_of_attendance").unwrap();
-    let custom_config = serde_json::from_slice::<CommonConfig>(&custom_config[..]).unwrap();
-
-    // Build and deploy the Proof of Attendance simplet
-    let mut builder = ProofOfAttendanceBuilder::new();
-
-    if let Some(secret) = config
-        .app_secret
-        .as_ref()
-        .or(custom_config.app_secret.as_ref())
-    {
-        builder = builder.app_secret(secret);
-    }
-    if let Some(url) = config.app_url.as_ref().or(custom_config.app_url.as_ref()) {
-        builder = builder.app_url(url);
-    }
-    if let Some(password) = config
-        .mysql_password
-        .as_ref()
-        .or(custom_config.mysql_password.as_ref())
-    {
-        builder = builder.mysql_password(password);
-    }
-    if let Some(db) = config.mysql_db.as_ref().or(custom_config.mysql_db.as_ref()) {
-        builder = builder.mysql_db(db);
-    }
-    if let Some(wallet) = config
-        .admin_wallet
-        .as_ref()
-        .or(custom_config.admin_wallet.as_ref())
-    {
-        builder = builder.admin_wallet(wallet);
-    }
-    if let (Some(key), Some(secret)) = (
-        config
-            .apillon_key
-            .as_ref()
-            .or(custom_config.apillon_key.as_ref()),
-        config
-            .apillon_secret
-            .as_ref()
-            .or(custom_config.apillon_secret.as_ref()),
-    ) {
-        builder = builder.apillon_credentials(key, secret);
-    }
-    if let Some(smtp) = config
-        .smtp_config
-        .as_ref()
-        .or(custom_config.smtp_config.as_ref())
-    {
-        builder = builder.smtp_config(simplets::SmtpConfig {
-            host: smtp.host.clone(),
-            port: smtp.port.clone(),
-            username: smtp.username.clone(),
-            password: smtp.password.clone(),
-            email_from: smtp.email_from.clone(),
-            name_from: smtp.name_from.clone(),
-        });
+    let kind = B::SERVICE_TYPE.key();
+    let operator_config = context
+        .simplet_configs
+        .get(kind)
+        .ok_or_else(|| SimpletError::MissingOperatorConfig(kind.to_string()))?;
+    let mut config = serde_json::from_slice::<B::Config>(custom_config)
+        .map_err(|e| SimpletError::InvalidConfig(e.to_string()))?;
+
+    *config.common_mut() = CommonConfig::merge(operator_config, config.common());
+    // Before anything is allocated or started for the instance
+    config.validate()?;
+    info!(
+        "Effective {} config: {:?}",
+        kind,
+        config.common().redacted()
+    );
+
+    let identity = InstanceIdentity {
+        service_id: context.service_id(),
+        owner: origin.caller.to_string(),
+        name: config.common().name.clone(),
+    };
+    let custom_domain = config
+        .common()
+        .custom_domain
+        .as_deref()
+        .map(domains::parse_domain)
+        .transpose()?;
+    if custom_domain.is_some() {
+        context.operator_config.proxy.check_custom_domains()?;
     }
+    let mut options = context
+        .operator_config
+        .deploy_options(B::SERVICE_TYPE, config.common().tier.as_deref())?;
+    let mut builder = B::from_config(config);
+    let config_hash = builder.get_config_fingerprint();
+    let instance_id = B::SERVICE_TYPE.instance_id(&identity.hash());
 
-    let unique_id = builder.get_unique_id();
-    match builder.deploy().await {
-        Ok(poa) => {
-            // Store the running service in the context
-            context
-                .running_services
-                .write()
-                .await
-                .insert(format!("proof_of_attendance_{}", unique_id), poa);
-            Ok("Proof of Attendance simplet deployed successfully!".to_string())
+    // Deploying the same instance twice must not fight over the same containers. Jobs
+    // working on an instance take it out of `running_services`, but never out of the registry.
+    let deployed_hash = context
+        .registry
+        .read()
+        .await
+        .get(&instance_id)
+        .map(|record| record.config_hash.clone());
+    if let Some(deployed_hash) = deployed_hash {
+        context.authorize(&instance_id, origin).await?;
+        if deployed_hash != config_hash {
+            return Err(SimpletError::ConfigConflict(instance_id));
         }
+        let existing = context
+            .running_services
+            .read()
+            .await
+            .get(&instance_id)
+            .cloned()
+            .ok_or_else(|| SimpletError::Busy(instance_id.clone()))?;
+        return redeploy_existing(existing).await;
+    }
+
+    // Generated after the fingerprint, so deploying the same config again finds the instance.
+    // The registry keeps the secret with the instance's other env vars.
+    if builder.get_config().common().app_secret.is_none() {
+        builder = builder.app_secret(simplets::random_app_secret());
+    }
+
+    // Route the app through the proxy when there is one, or publish it on its own port
+    let operator_config = &context.operator_config;
+    let slug = B::SERVICE_TYPE.slug(&identity.hash());
+    let (endpoint, host_port) = match operator_config.proxy.endpoint(&slug) {
+        Some(endpoint) => {
+            let docker = connect_to_docker(None).await?;
+            proxy::ensure_proxy(
+                &docker,
+                context.service_id(),
+                &operator_config.proxy,
+                &operator_config.registries,
+                &context.proxy_dir(),
+            )
+            .await?;
+            (endpoint, None)
+        }
+        None => {
+            let host_port = context.ports.lock().await.allocate()?;
+            (
+                operator_config.endpoints.endpoint(host_port),
+                Some(host_port),
+            )
+        }
+    };
+
+    options.endpoint = Some(endpoint);
+    let service = match builder.deploy(&identity, &options).await {
+        Ok(service) => service,
         Err(e) => {
-            // Since we're returning Result<String, Infallible>, we need to handle any error
-            // by panicking since Infallible means this function cannot fail
-            error!("Failed to deploy Proof of Attendance simplet: {:?}", e);
-            Ok("Failed to deploy Proof of Attendance simplet!".to_string())
+            sdk::error!("Failed to deploy {} simplet: {}", kind, e);
+            if let Some(host_port) = host_port {
+                context.ports.lock().await.release(host_port);
+            }
+            return Err(e);
         }
+    };
+
+    let result = DeployResult {
+        instance_id: service.instance_id().to_string(),
+        service_type: service.service_type(),
+        url: service.app_url(),
+        public_url: options.endpoint.map(|endpoint| endpoint.public_url),
+        already_running: false,
+        domain_challenge: custom_domain
+            .map(|domain| DomainChallenge::new(service.instance_id(), &domain)),
+    };
+
+    // Store the running service in the context
+    context.track(service, config_hash, origin).await;
+    if let Some(challenge) = &result.domain_challenge {
+        context
+            .set_pending_domain(&result.instance_id, Some(challenge.clone()))
+            .await?;
     }
+    Ok(serde_json::to_string(&result).expect("result should serialize"))
 }
 
+/// Result returned by the deploy jobs, serialized as JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeployResult {
+    pub instance_id: String,
+    pub service_type: ServiceType,
+    pub url: String,
+    /// URL the app is published under on the operator's host
+    pub public_url: Option<String>,
+    /// Whether an instance with the same id was already up and left untouched
+    pub already_running: bool,
+    /// TXT record to publish before the custom domain is routed, see [`verify_domain`]
+    pub domain_challenge: Option<DomainChallenge>,
+}
+
+/// Return an already deployed instance, restarting it if it went down
+async fn redeploy_existing(service: ApillonSimpletsDocker) -> Result<String, SimpletError> {
+    let public_url = service
+        .endpoint()
+        .map(|endpoint| endpoint.public_url.clone());
+    let status = service.status().await?;
+    let already_running = match status.phase {
+        InstancePhase::Healthy | InstancePhase::Starting => true,
+        InstancePhase::Degraded | InstancePhase::Stopped => {
+            if let Some(missing) = status.containers.iter().find(|c| c.state.is_none()) {
+                return Err(SimpletError::Unrecoverable {
+                    instance_id: status.instance_id,
+                    reason: format!("its {} container is gone, stop it first", missing.role),
+                });
+            }
+
+            info!("Resuming simplet {}", status.instance_id);
+            service.resume().await?;
+            false
+        }
+    };
+
+    let result = DeployResult {
+        instance_id: status.instance_id,
+        service_type: status.service_type,
+        url: status.url,
+        public_url,
+        already_running,
+        domain_challenge: None,
+    };
+    Ok(serde_json::to_string(&result).expect("result should serialize"))
+}
+
+/// Result returned by [`stop_simplet`], serialized as JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StopSimpletResult {
+    pub instance_id: String,
+    pub message: String,
+    /// Data volumes kept for a later deploy of the same instance, empty if purged
+    pub retained_volumes: Vec<String>,
+}
+
+/// Stop and remove an instance, deleting its data too if `purge_data` is set
 #[sdk::job(
-    id = 1,
-    params(custom_config),
+    id = 2,
+    params(instance_id, purge_data),
+    result(_),
+    event_listener(
+        listener = TangleEventListener::<SimpletsContext, JobCalled>,
+        pre_processor = simplets_pre_processor,
+    ),
+)]
+pub async fn stop_simplet(
+    instance_id: String,
+    purge_data: bool,
+    context: SimpletsContext,
+) -> Result<String, SimpletError> {
+    let call_id = STOP_SIMPLET_ACTIVE_CALL_ID.load(Ordering::Relaxed);
+    let origin = context.take_origin(call_id).await?;
+    context.authorize(&instance_id, &origin).await?;
+
+    // Take the service out of the map so concurrent calls can't tear it down twice
+    let Some(service) = context.running_services.write().await.remove(&instance_id) else {
+        return Err(SimpletError::Busy(instance_id));
+    };
+
+    if let Err(e) = service.stop().await {
+        // Removal below is forced, so a failed graceful stop is not fatal
+        warn!("Failed to stop simplet {} gracefully: {}", instance_id, e);
+    }
+
+    let (volumes, retained_volumes) = if purge_data {
+        (VolumePolicy::Purge, Vec::new())
+    } else {
+        (VolumePolicy::Retain, service.volume_names())
+    };
+    if let Err(e) = service.clone().cleanup(volumes).await {
+        sdk::error!("Failed to remove simplet {}: {}", instance_id, e);
+        // Keep tracking the instance so the stop can be retried
+        context
+            .running_services
+            .write()
+            .await
+            .insert(instance_id, service);
+        return Err(e);
+    }
+
+    context.untrack(&instance_id).await;
+    info!("Stopped and removed simplet {}", instance_id);
+
+    let result = StopSimpletResult {
+        instance_id,
+        message: "Instance stopped and removed".to_string(),
+        retained_volumes,
+    };
+    Ok(serde_json::to_string(&result).expect("result should serialize"))
+}
+
+#[sdk::job(
+    id = 3,
+    params(instance_id),
     result(_),
     event_listener(
         listener = TangleEventListener::<SimpletsContext, JobCalled>,
         pre_processor = services_pre_processor,
     ),
 )]
-pub async fn run_email_airdrop_simplet(
-    custom_config: Vec<u8>,
+pub async fn simplet_status(
+    instance_id: String,
     context: SimpletsContext,
-) -> Result<String, Infallible> {
-    let config = context.simplet_configs.get("email_airdrop").unwrap();
-    let custom_config = serde_json::from_slice::<CommonConfig>(&custom_config[..]).unwrap();
-
-    // Build and deploy the Email Airdrop simplet
-    let mut builder = EmailAirdropBuilder::new();
-
-    if let Some(secret) = config
-        .app_secret
-        .as_ref()
-        .or(custom_config.app_secret.as_ref())
-    {
-        builder = builder.app_secret(secret);
-    }
-    if let Some(url) = config.app_url.as_ref().or(custom_config.app_url.as_ref()) {
-        builder = builder.app_url(url);
-    }
-    if let Some(password) = config
-        .mysql_password
-        .as_ref()
-        .or(custom_config.mysql_password.as_ref())
-    {
-        builder = builder.mysql_password(password);
-    }
-    if let Some(db) = config.mysql_db.as_ref().or(custom_config.mysql_db.as_ref()) {
-        builder = builder.mysql_db(db);
-    }
-    if let Some(wallet) = config
-        .admin_wallet
-        .as_ref()
-        .or(custom_config.admin_wallet.as_ref())
-    {
-        builder = builder.admin_wallet(wallet);
-    }
-    if let (Some(key), Some(secret)) = (
-        config
-            .apillon_key
-            .as_ref()
-            .or(custom_config.apillon_key.as_ref()),
-        config
-            .apillon_secret
-            .as_ref()
-            .or(custom_config.apillon_secret.as_ref()),
-    ) {
-        builder = builder.apillon_credentials(key, secret);
-    }
-    if let Some(smtp) = config
-        .smtp_config
-        .as_ref()
-        .or(custom_config.smtp_config.as_ref())
-    {
-        builder = builder.smtp_config(simplets::SmtpConfig {
-            host: smtp.host.clone(),
-            port: smtp.port.clone(),
-            username: smtp.username.clone(),
-            password: smtp.password.clone(),
-            email_from: smtp.email_from.clone(),
-            name_from: smtp.name_from.clone(),
+) -> Result<String, SimpletError> {
+    let service = context
+        .running_services
+        .read()
+        .await
+        .get(&instance_id)
+        .cloned()
+        .ok_or_else(|| SimpletError::InstanceNotFound(instance_id.clone()))?;
+
+    let mut status = service.status().await?;
+    status.certificates = context
+        .registry
+        .read()
+        .await
+        .get(&instance_id)
+        .map(|record| record.certificates.clone())
+        .unwrap_or_default();
+    Ok(serde_json::to_string(&status).expect("status should serialize"))
+}
+
+/// One entry of the [`list_simplets`] result
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InstanceSummary {
+    pub instance_id: String,
+    pub service_type: ServiceType,
+    pub created_at: u64,
+    pub created_at_block: Option<u32>,
+    pub url: String,
+    /// `None` if the containers could not be inspected
+    pub phase: Option<InstancePhase>,
+}
+
+#[sdk::job(
+    id = 4,
+    params(service_type),
+    result(_),
+    event_listener(
+        listener = TangleEventListener::<SimpletsContext, JobCalled>,
+        pre_processor = simplets_pre_processor,
+    ),
+)]
+pub async fn list_simplets(
+    service_type: String,
+    context: SimpletsContext,
+) -> Result<String, SimpletError> {
+    let call_id = LIST_SIMPLETS_ACTIVE_CALL_ID.load(Ordering::Relaxed);
+    let origin = context.take_origin(call_id).await?;
+
+    // An empty filter lists every service type
+    let filter = match service_type.as_str() {
+        "" => None,
+        key => Some(ServiceType::from_key(key).ok_or_else(|| {
+            SimpletError::InvalidConfig(format!("unknown service type `{}`", key))
+        })?),
+    };
+
+    let records = context
+        .registry
+        .read()
+        .await
+        .owned_by(&origin.caller)
+        .filter(|record| filter.map_or(true, |filter| record.service_type == filter))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut instances = Vec::with_capacity(records.len());
+    for record in records {
+        let service = context
+            .running_services
+            .read()
+            .await
+            .get(&record.instance_id)
+            .cloned();
+        // The URL is known without Docker, only the phase needs the live status
+        let phase = match service {
+            Some(service) => service.status().await.ok().map(|status| status.phase),
+            None => None,
+        };
+
+        instances.push(InstanceSummary {
+            url: record.app_url(),
+            phase,
+            instance_id: record.instance_id,
+            service_type: record.service_type,
+            created_at: record.created_at,
+            created_at_block: record.created_at_block,
         });
     }
 
-    let unique_id = builder.get_unique_id();
-    match builder.deploy().await {
-        Ok(airdrop) => {
-            // Store the running service in the context
-            context
-                .running_services
-                .write()
-                .await
-                .insert(format!("email_airdrop_{}", unique_id), airdrop);
-            Ok("Email Airdrop simplet deployed successfully!".to_string())
+    Ok(serde_json::to_string(&instances).expect("instances should serialize"))
+}
+
+/// Result returned by [`update_simplet`], serialized as JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpdateSimpletResult {
+    pub instance_id: String,
+    pub url: String,
+    /// Env vars whose value changed, without their values since some are secrets
+    pub changed: Vec<String>,
+    /// TXT record to publish before the requested custom domain is routed
+    pub domain_challenge: Option<DomainChallenge>,
+}
+
+#[sdk::job(
+    id = 5,
+    params(instance_id, partial_config),
+    result(_),
+    event_listener(
+        listener = TangleEventListener::<SimpletsContext, JobCalled>,
+        pre_processor = simplets_pre_processor,
+    ),
+)]
+pub async fn update_simplet(
+    instance_id: String,
+    partial_config: Vec<u8>,
+    context: SimpletsContext,
+) -> Result<String, SimpletError> {
+    let call_id = UPDATE_SIMPLET_ACTIVE_CALL_ID.load(Ordering::Relaxed);
+    let origin = context.take_origin(call_id).await?;
+    context.authorize(&instance_id, &origin).await?;
+
+    // Take the service out of the map so concurrent calls can't replace its app together
+    let Some(mut service) = context.running_services.write().await.remove(&instance_id) else {
+        return Err(SimpletError::Busy(instance_id));
+    };
+
+    let result = async {
+        let update = parse_update(service.service_type(), &partial_config)?;
+        if update.custom_domain.is_some() {
+            context.operator_config.proxy.check_custom_domains()?;
         }
-        Err(e) => {
-            // Since we're returning Result<String, Infallible>, we need to handle any error
-            // by panicking since Infallible means this function cannot fail
-            error!("Failed to deploy Email Airdrop simplet: {:?}", e);
-            Ok("Failed to deploy Email Airdrop simplet!".to_string())
+        let changed = apply_update(&mut service, update.env_vars).await?;
+        Ok::<_, SimpletError>((changed, update.custom_domain))
+    }
+    .await;
+    if result.is_ok() {
+        context.record_update(&service).await;
+    }
+    context
+        .running_services
+        .write()
+        .await
+        .insert(instance_id.clone(), service.clone());
+
+    let (changed, custom_domain) = result.inspect_err(|e| {
+        sdk::error!("Failed to update simplet {}: {}", instance_id, e);
+    })?;
+    info!("Updated {:?} of simplet {}", changed, instance_id);
+
+    let domain_challenge = custom_domain.map(|domain| DomainChallenge::new(&instance_id, &domain));
+    if let Some(challenge) = &domain_challenge {
+        context
+            .set_pending_domain(&instance_id, Some(challenge.clone()))
+            .await?;
+    }
+
+    let result = UpdateSimpletResult {
+        instance_id,
+        url: service.app_url(),
+        changed,
+        domain_challenge,
+    };
+    Ok(serde_json::to_string(&result).expect("result should serialize"))
+}
+
+/// Parse a partial config against the config type of `service_type`
+fn parse_update(
+    service_type: ServiceType,
+    partial_config: &[u8],
+) -> Result<simplets::PartialUpdate, SimpletError> {
+    match service_type {
+        ServiceType::ProofOfAttendance => {
+            simplets::partial_update::<ProofOfAttendanceConfig>(partial_config)
         }
+        ServiceType::EmailAirdrop => simplets::partial_update::<EmailAirdropConfig>(partial_config),
+    }
+}
+
+/// Merge env var changes into the env vars of `service` and roll its app onto them
+async fn apply_update(
+    service: &mut ApillonSimpletsDocker,
+    changes: HashMap<String, String>,
+) -> Result<Vec<String>, SimpletError> {
+    let mut env_vars = service.env_vars().clone();
+    let mut changed: Vec<String> = changes
+        .iter()
+        .filter(|(key, value)| env_vars.get(*key) != Some(*value))
+        .map(|(key, _)| key.clone())
+        .collect();
+    changed.sort();
+    if changed.is_empty() {
+        return Ok(changed);
     }
+
+    env_vars.extend(changes);
+    service.service_type().validate_env_vars(&env_vars)?;
+    service.update_env(env_vars).await?;
+    Ok(changed)
+}
+
+/// Result returned by [`upgrade_simplet`], serialized as JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpgradeSimpletResult {
+    pub instance_id: String,
+    pub previous_image: String,
+    pub image: String,
+}
+
+/// Move an instance's app to another tag of its image, or to the operator's pinned
+/// image if `tag` is empty
+///
+/// The previous app container is restored if the new one fails its health check.
+#[sdk::job(
+    id = 6,
+    params(instance_id, tag),
+    result(_),
+    event_listener(
+        listener = TangleEventListener::<SimpletsContext, JobCalled>,
+        pre_processor = simplets_pre_processor,
+    ),
+)]
+pub async fn upgrade_simplet(
+    instance_id: String,
+    tag: String,
+    context: SimpletsContext,
+) -> Result<String, SimpletError> {
+    let call_id = UPGRADE_SIMPLET_ACTIVE_CALL_ID.load(Ordering::Relaxed);
+    let origin = context.take_origin(call_id).await?;
+    context.authorize(&instance_id, &origin).await?;
+
+    // Take the service out of the map so concurrent calls can't replace its app together
+    let Some(mut service) = context.running_services.write().await.remove(&instance_id) else {
+        return Err(SimpletError::Busy(instance_id));
+    };
+
+    // Callers pick a tag, never a repository, so they can only run images the operator trusts
+    let pinned = context.operator_config.images(service.service_type()).app;
+    let image = match tag.trim() {
+        "" => pinned,
+        tag => simplets::retag_image(&pinned, tag),
+    };
+    let previous_image = service.images().app.clone();
+
+    let result = if image == previous_image {
+        Ok(())
+    } else {
+        service.upgrade(image.clone()).await
+    };
+    if result.is_ok() {
+        context.record_update(&service).await;
+    }
+    context
+        .running_services
+        .write()
+        .await
+        .insert(instance_id.clone(), service);
+
+    result.inspect_err(|e| {
+        sdk::error!(
+            "Failed to upgrade simplet {} to {}: {}",
+            instance_id,
+            image,
+            e
+        );
+    })?;
+    info!(
+        "Upgraded simplet {} from {} to {}",
+        instance_id, previous_image, image
+    );
+
+    let result = UpgradeSimpletResult {
+        instance_id,
+        previous_image,
+        image,
+    };
+    Ok(serde_json::to_string(&result).expect("result should serialize"))
+}
+
+/// Result returned by [`verify_domain`], serialized as JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VerifyDomainResult {
+    pub instance_id: String,
+    pub domain: String,
+    pub url: String,
+}
+
+/// Route the custom domain requested for an instance once its owner published the
+/// challenge token in DNS
+///
+/// The app is recreated with `APP_URL` pointing at the domain, and the previous container
+/// is restored if the new one fails its health check.
+#[sdk::job(
+    id = 7,
+    params(instance_id),
+    result(_),
+    event_listener(
+        listener = TangleEventListener::<SimpletsContext, JobCalled>,
+        pre_processor = simplets_pre_processor,
+    ),
+)]
+pub async fn verify_domain(
+    instance_id: String,
+    context: SimpletsContext,
+) -> Result<String, SimpletError> {
+    let call_id = VERIFY_DOMAIN_ACTIVE_CALL_ID.load(Ordering::Relaxed);
+    let origin = context.take_origin(call_id).await?;
+    context.authorize(&instance_id, &origin).await?;
+
+    let challenge = {
+        let registry = context.registry.read().await;
+        let challenge = registry
+            .get(&instance_id)
+            .and_then(|record| record.pending_domain.clone())
+            .ok_or_else(|| {
+                SimpletError::InvalidConfig(format!(
+                    "no custom domain is pending for simplet {}",
+                    instance_id
+                ))
+            })?;
+
+        // Two routers for the same host would make the proxy pick one at random
+        let routed_elsewhere = registry.instances().find(|record| {
+            record.instance_id != instance_id
+                && record
+                    .endpoint
+                    .as_ref()
+                    .and_then(|e| e.custom_domain.as_ref())
+                    == Some(&challenge.domain)
+        });
+        if let Some(other) = routed_elsewhere {
+            return Err(SimpletError::InvalidConfig(format!(
+                "{} is already routed to simplet {}",
+                challenge.domain, other.instance_id
+            )));
+        }
+        challenge
+    };
+    let proxy_config = &context.operator_config.proxy;
+    proxy_config.check_certificate(&challenge.domain)?;
+    challenge.verify().await.inspect_err(|e| {
+        warn!(
+            "Custom domain of simplet {} not verified: {}",
+            instance_id, e
+        );
+    })?;
+
+    // Take the service out of the map so concurrent calls can't replace its app together
+    let Some(mut service) = context.running_services.write().await.remove(&instance_id) else {
+        return Err(SimpletError::Busy(instance_id));
+    };
+
+    // Certificate files are handed to the proxy when it is set up, so pick up the new one
+    let docker = connect_to_docker(None).await?;
+    let result = proxy::ensure_proxy(
+        &docker,
+        context.service_id(),
+        proxy_config,
+        &context.operator_config.registries,
+        &context.proxy_dir(),
+    )
+    .await;
+    let url = proxy_config.url(&challenge.domain);
+    let result = match result {
+        Ok(()) => {
+            service
+                .add_custom_domain(&challenge.domain, url.clone())
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    if result.is_ok() {
+        context.record_update(&service).await;
+    }
+    context
+        .running_services
+        .write()
+        .await
+        .insert(instance_id.clone(), service);
+
+    result.inspect_err(|e| {
+        sdk::error!(
+            "Failed to route {} to simplet {}: {}",
+            challenge.domain,
+            instance_id,
+            e
+        );
+    })?;
+    context.set_pending_domain(&instance_id, None).await?;
+    info!("Routed {} to simplet {}", challenge.domain, instance_id);
+
+    let result = VerifyDomainResult {
+        instance_id,
+        domain: challenge.domain,
+        url,
+    };
+    Ok(serde_json::to_string(&result).expect("result should serialize"))
 }
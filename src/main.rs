@@ -1,13 +1,24 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use apillon_simplet_blueprint_template as blueprint;
+use blueprint::operator_config::OperatorConfig;
+use blueprint::ports::PortAllocator;
+use blueprint::proxy;
+use blueprint::reconcile::reconcile;
+use blueprint::registry::{InstanceRegistry, REGISTRY_FILE};
+use blueprint::simplets::{image, ApillonSimpletsDocker};
 use color_eyre::Result;
 use gadget_sdk as sdk;
+use gadget_sdk::docker::connect_to_docker;
 use gadget_sdk::runners::tangle::TangleConfig;
 use gadget_sdk::runners::BlueprintRunner;
 use sdk::tangle_subxt::*;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
+
+/// How often the certificates of instances served over TLS are checked
+const CERTIFICATE_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 #[sdk::main(env)]
 async fn main() -> Result<()> {
@@ -16,12 +27,87 @@ async fn main() -> Result<()> {
 
     let service_id = env.service_id().expect("should exist");
 
+    // Fail fast on a malformed operator config rather than on the first deploy
+    let operator_config = OperatorConfig::from_env()?;
+    let simplet_configs = operator_config.simplet_configs();
+
+    // Pick up the instances deployed before the last restart and bring Docker in line with them
+    let data_dir = blueprint::data_dir(&env);
+    let mut registry = InstanceRegistry::load(data_dir.join(REGISTRY_FILE))?;
+    let docker = connect_to_docker(None).await?;
+    let report = reconcile(
+        docker.clone(),
+        &mut registry,
+        service_id,
+        operator_config.readiness,
+    )
+    .await?;
+    tracing::info!(
+        "Reconciled simplets: {} restarted, {} adopted, {} removed, {} forgotten, {} failed",
+        report.restarted.len(),
+        report.adopted.len(),
+        report.removed.len(),
+        report.forgotten.len(),
+        report.failed.len()
+    );
+
+    // Pull the pinned images in the background so the first deploy doesn't wait on them
+    let prefetch_docker = docker.clone();
+    let images = operator_config.all_images();
+    let registries = operator_config.registries.clone();
+    tokio::spawn(async move {
+        image::prefetch(&prefetch_docker, images, &registries).await;
+    });
+
+    let running_services = registry
+        .instances()
+        .map(|record| {
+            let service = ApillonSimpletsDocker::from_record(docker.clone(), record)
+                .with_service_id(service_id)
+                .with_registries(operator_config.registries.clone())
+                .with_readiness(operator_config.readiness);
+            (record.instance_id.clone(), service)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let ports = PortAllocator::new(
+        operator_config.endpoints.ports(),
+        registry
+            .instances()
+            .filter_map(|record| record.endpoint.as_ref().and_then(|e| e.host_port)),
+    );
+
     let context = blueprint::SimpletsContext {
+        operator_config: Arc::new(operator_config),
         config: env.clone(),
-        simplet_configs: HashMap::new(),
-        running_services: Arc::new(RwLock::new(HashMap::new())),
+        simplet_configs,
+        running_services: Arc::new(RwLock::new(running_services)),
+        registry: Arc::new(RwLock::new(registry)),
+        origins: Arc::new(RwLock::new(HashMap::new())),
+        ports: Arc::new(Mutex::new(ports)),
     };
 
+    if context.operator_config.proxy.is_enabled() {
+        proxy::ensure_proxy(
+            &docker,
+            service_id,
+            &context.operator_config.proxy,
+            &context.operator_config.registries,
+            &context.proxy_dir(),
+        )
+        .await?;
+    }
+
+    // Keep track of certificate expiry for the instances served over TLS
+    let certificates_context = context.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CERTIFICATE_REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            certificates_context.refresh_certificates().await;
+        }
+    });
+
     // Create the event handler from the job
     let run_poa_simplet = blueprint::RunProofOfAttendanceSimpletEventHandler {
         service_id,
@@ -37,10 +123,58 @@ async fn main() -> Result<()> {
         context: context.clone(),
     };
 
+    let stop_simplet = blueprint::StopSimpletEventHandler {
+        service_id,
+        client: client.clone(),
+        signer: signer.clone(),
+        context: context.clone(),
+    };
+
+    let simplet_status = blueprint::SimpletStatusEventHandler {
+        service_id,
+        client: client.clone(),
+        signer: signer.clone(),
+        context: context.clone(),
+    };
+
+    let list_simplets = blueprint::ListSimpletsEventHandler {
+        service_id,
+        client: client.clone(),
+        signer: signer.clone(),
+        context: context.clone(),
+    };
+
+    let update_simplet = blueprint::UpdateSimpletEventHandler {
+        service_id,
+        client: client.clone(),
+        signer: signer.clone(),
+        context: context.clone(),
+    };
+
+    let upgrade_simplet = blueprint::UpgradeSimpletEventHandler {
+        service_id,
+        client: client.clone(),
+        signer: signer.clone(),
+        context: context.clone(),
+    };
+
+    let verify_domain = blueprint::VerifyDomainEventHandler {
+        service_id,
+        client: client.clone(),
+        signer: signer.clone(),
+        context: context.clone(),
+    };
+
     tracing::info!("Starting the event watcher ...");
     BlueprintRunner::new(TangleConfig::default(), env)
         .job(run_poa_simplet)
         .job(run_email_airdrop)
+        .job(stop_simplet)
+        .job(simplet_status)
+        .job(list_simplets)
+        .job(update_simplet)
+        .job(upgrade_simplet)
+        .job(verify_domain)
         .run()
         .await?;
 
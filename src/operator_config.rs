@@ -0,0 +1,413 @@
+use crate::error::SimpletError;
+use crate::proxy::ProxyConfig;
+use crate::simplets::image::RegistryAuth;
+use crate::simplets::limits::InstanceLimits;
+use crate::simplets::{
+    CommonConfig, DeployOptions, Endpoint, ImageRefs, MergePolicy, ReadinessConfig, ServiceType,
+};
+use gadget_sdk::event_listener::tangle::AccountId32;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// Environment variable holding the path of the operator's simplet configuration
+pub const OPERATOR_CONFIG_ENV: &str = "SIMPLETS_CONFIG";
+
+/// Infrastructure settings the operator provides once for every deployment
+///
+/// The file is TOML unless its extension is `.json`:
+///
+/// ```toml
+/// admins = ["5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY"]
+///
+/// [images]
+/// proof_of_attendance = "ps-poa:1.4.2"
+/// mysql = "mysql:8.4"
+///
+/// [endpoints]
+/// public_host = "simplets.example.com"
+/// port_range = [20000, 20999]
+///
+/// [proxy]
+/// domain = "simplets.example.com"
+///
+/// [proxy.tls]
+/// source = "acme"
+/// email = "ops@example.com"
+///
+/// [limits.proof_of_attendance.app]
+/// memory_mb = 1024
+/// cpus = 1.5
+///
+/// [tiers.large.app]
+/// memory_mb = 4096
+/// cpus = 4.0
+/// pids = 1024
+///
+/// [readiness]
+/// timeout_secs = 300
+/// interval_secs = 5
+///
+/// [[registries]]
+/// server = "ghcr.io"
+/// username = "operator"
+/// password = "..."
+///
+/// [simplets.proof_of_attendance]
+/// apillon_key = "..."
+/// apillon_secret = "..."
+///
+/// [simplets.proof_of_attendance.smtp_config]
+/// host = "smtp.example.com"
+/// port = "587"
+/// username = "relay"
+/// password = "..."
+/// email_from = "events@example.com"
+/// name_from = "Example Events"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OperatorConfig {
+    /// Accounts allowed to manage any instance, regardless of who deployed it
+    ///
+    /// Addresses may use any SS58 format, they are compared by account.
+    #[serde(default)]
+    pub admins: Vec<AccountId32>,
+    /// Defaults for each simplet, keyed by [`ServiceType::key`]
+    #[serde(default)]
+    pub simplets: HashMap<String, CommonConfig>,
+    /// Images of each simplet keyed by [`ServiceType::key`], and of their databases
+    /// under [`DB_IMAGE_KEY`]
+    #[serde(default)]
+    pub images: HashMap<String, String>,
+    /// Credentials for private registries images are pulled from
+    #[serde(default)]
+    pub registries: Vec<RegistryAuth>,
+    /// Where app containers are published
+    #[serde(default)]
+    pub endpoints: EndpointConfig,
+    /// Reverse proxy routing host names to apps, used instead of host ports when enabled
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    /// Resource limits of each simplet's containers keyed by [`ServiceType::key`], on top of
+    /// [`InstanceLimits::baseline`]
+    #[serde(default)]
+    pub limits: HashMap<String, InstanceLimits>,
+    /// Limits callers may request with the `tier` of their deploy config, by tier name
+    #[serde(default)]
+    pub tiers: HashMap<String, InstanceLimits>,
+    /// How long deploys, updates and restarts wait for containers to become healthy
+    #[serde(default)]
+    pub readiness: ReadinessConfig,
+}
+
+/// How app containers are made reachable from outside the host
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndpointConfig {
+    /// Host name or address clients reach this host under
+    pub public_host: String,
+    /// First and last host port apps are published on
+    pub port_range: [u16; 2],
+}
+
+impl Default for EndpointConfig {
+    fn default() -> Self {
+        Self {
+            public_host: "localhost".to_string(),
+            port_range: [20000, 20999],
+        }
+    }
+}
+
+impl EndpointConfig {
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.port_range[0]..=self.port_range[1]
+    }
+
+    /// The endpoint of an app published on `host_port`
+    pub fn endpoint(&self, host_port: u16) -> Endpoint {
+        Endpoint {
+            host_port: Some(host_port),
+            hostname: None,
+            custom_domain: None,
+            tls: false,
+            cert_resolver: None,
+            public_url: format!("http://{}:{}", self.public_host, host_port),
+        }
+    }
+}
+
+/// Key of the MySQL image in [`OperatorConfig::images`]
+pub const DB_IMAGE_KEY: &str = "mysql";
+
+impl OperatorConfig {
+    /// Load the file named by [`OPERATOR_CONFIG_ENV`], or an empty config if it is unset
+    pub fn from_env() -> Result<Self, SimpletError> {
+        match std::env::var_os(OPERATOR_CONFIG_ENV) {
+            Some(path) => Self::load(path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SimpletError> {
+        let path = path.as_ref();
+        let invalid = |reason: String| {
+            SimpletError::OperatorConfig(format!("{}: {}", path.display(), reason))
+        };
+
+        let contents = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let config: Self = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&contents).map_err(|e| invalid(e.to_string()))?
+        } else {
+            toml::from_str(&contents).map_err(|e| invalid(e.to_string()))?
+        };
+
+        if let Some(unknown) = config
+            .simplets
+            .keys()
+            .find(|key| ServiceType::from_key(key).is_none())
+        {
+            return Err(invalid(format!("unknown simplet `{}`", unknown)));
+        }
+        // Operator values of caller-only fields would be silently ignored
+        for (key, simplet) in &config.simplets {
+            let set = serde_json::to_value(simplet).map_err(|e| invalid(e.to_string()))?;
+            if let Some((field, _)) = CommonConfig::POLICIES
+                .iter()
+                .find(|(field, policy)| *policy == MergePolicy::CallerOnly && !set[field].is_null())
+            {
+                return Err(invalid(format!(
+                    "simplets.{}.{} is chosen by each caller",
+                    key, field
+                )));
+            }
+        }
+        if let Some(unknown) = config
+            .images
+            .keys()
+            .find(|key| *key != DB_IMAGE_KEY && ServiceType::from_key(key).is_none())
+        {
+            return Err(invalid(format!("unknown image `{}`", unknown)));
+        }
+        if let Some(unknown) = config
+            .limits
+            .keys()
+            .find(|key| ServiceType::from_key(key).is_none())
+        {
+            return Err(invalid(format!("unknown limits `{}`", unknown)));
+        }
+        if config.readiness.interval.is_zero() {
+            return Err(invalid("readiness.interval_secs must not be 0".to_string()));
+        }
+        if config.endpoints.ports().is_empty() {
+            return Err(invalid("empty endpoints.port_range".to_string()));
+        }
+
+        Ok(config)
+    }
+
+    /// Defaults for every known simplet, empty where the operator configured none
+    pub fn simplet_configs(&self) -> HashMap<String, CommonConfig> {
+        [ServiceType::ProofOfAttendance, ServiceType::EmailAirdrop]
+            .into_iter()
+            .map(|service_type| {
+                let key = service_type.key();
+                let config = self.simplets.get(key).cloned().unwrap_or_default();
+                (key.to_string(), config)
+            })
+            .collect()
+    }
+
+    /// Images new instances of `service_type` are deployed with
+    pub fn images(&self, service_type: ServiceType) -> ImageRefs {
+        let defaults = ImageRefs::defaults(service_type);
+        ImageRefs {
+            app: self
+                .images
+                .get(service_type.key())
+                .cloned()
+                .unwrap_or(defaults.app),
+            db: self
+                .images
+                .get(DB_IMAGE_KEY)
+                .cloned()
+                .unwrap_or(defaults.db),
+        }
+    }
+
+    /// Images of every known simplet and their databases
+    pub fn all_images(&self) -> Vec<String> {
+        [ServiceType::ProofOfAttendance, ServiceType::EmailAirdrop]
+            .into_iter()
+            .flat_map(|service_type| {
+                let images = self.images(service_type);
+                [images.app, images.db]
+            })
+            .collect()
+    }
+
+    /// Resource limits of new instances of `service_type` in `tier`, or in no tier
+    pub fn limits(
+        &self,
+        service_type: ServiceType,
+        tier: Option<&str>,
+    ) -> Result<InstanceLimits, SimpletError> {
+        let mut limits = InstanceLimits::baseline();
+        if let Some(defaults) = self.limits.get(service_type.key()) {
+            limits = limits.overlay(defaults);
+        }
+
+        let Some(tier) = tier else {
+            return Ok(limits);
+        };
+        let overrides = self.tiers.get(tier).ok_or_else(|| {
+            let mut tiers = self.tiers.keys().map(String::as_str).collect::<Vec<_>>();
+            tiers.sort();
+            SimpletError::InvalidConfig(format!(
+                "unknown tier `{}`, the operator offers {:?}",
+                tier, tiers
+            ))
+        })?;
+        Ok(limits.overlay(overrides))
+    }
+
+    /// Options new instances of `service_type` in `tier` are deployed with
+    pub fn deploy_options(
+        &self,
+        service_type: ServiceType,
+        tier: Option<&str>,
+    ) -> Result<DeployOptions, SimpletError> {
+        Ok(DeployOptions {
+            images: Some(self.images(service_type)),
+            registries: self.registries.clone(),
+            endpoint: None,
+            limits: Some(self.limits(service_type, tier)?),
+            readiness: self.readiness,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_operator_config() {
+        let config: OperatorConfig = toml::from_str(
+            r#"
+            admins = ["5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY"]
+
+            [images]
+            email_airdrop = "ps-email-airdrop:2.0.1"
+
+            [limits.email_airdrop.db]
+            memory_mb = 2048
+
+            [tiers.large.app]
+            memory_mb = 4096
+            cpus = 4.0
+
+            [readiness]
+            timeout_secs = 300
+
+            [simplets.email_airdrop]
+            apillon_key = "key"
+            apillon_secret = "secret"
+
+            [simplets.email_airdrop.smtp_config]
+            host = "smtp.example.com"
+            port = "587"
+            username = "relay"
+            password = "password"
+            email_from = "events@example.com"
+            name_from = "Example Events"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.admins[0].to_string(),
+            "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY"
+        );
+
+        let configs = config.simplet_configs();
+        let airdrop = &configs["email_airdrop"];
+        assert_eq!(airdrop.apillon_key.as_deref(), Some("key"));
+        assert_eq!(airdrop.smtp_config.as_ref().unwrap().port, "587");
+        assert!(configs["proof_of_attendance"].apillon_key.is_none());
+
+        let images = config.images(ServiceType::EmailAirdrop);
+        assert_eq!(images.app, "ps-email-airdrop:2.0.1");
+        assert_eq!(images.db, "mysql");
+
+        assert_eq!(
+            config.readiness.timeout,
+            std::time::Duration::from_secs(300)
+        );
+        assert_eq!(
+            config.readiness.interval,
+            ReadinessConfig::default().interval
+        );
+
+        let baseline = InstanceLimits::baseline();
+        let limits = config.limits(ServiceType::EmailAirdrop, None).unwrap();
+        assert_eq!(limits.db.memory_mb, Some(2048));
+        assert_eq!(limits.app, baseline.app);
+        let large = config
+            .limits(ServiceType::ProofOfAttendance, Some("large"))
+            .unwrap();
+        assert_eq!(large.app.memory_mb, Some(4096));
+        assert_eq!(large.app.pids, baseline.app.pids);
+        assert_eq!(large.db, baseline.db);
+        assert!(matches!(
+            config.limits(ServiceType::EmailAirdrop, Some("huge")),
+            Err(SimpletError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_admins_in_any_ss58_format() {
+        use gadget_sdk::subxt_core::ext::sp_core::crypto::{self, Ss58AddressFormat, Ss58Codec};
+
+        // Tangle's own address format, while callers are displayed in the generic one
+        let tangle_address = crypto::AccountId32::from([7; 32])
+            .to_ss58check_with_version(Ss58AddressFormat::custom(5845));
+        let path =
+            std::env::temp_dir().join(format!("simplets-admins-{}.toml", std::process::id()));
+
+        std::fs::write(&path, format!("admins = [\"{}\"]\n", tangle_address)).unwrap();
+        let config = OperatorConfig::load(&path).unwrap();
+        std::fs::write(&path, "admins = [\"admin\"]\n").unwrap();
+        let invalid = OperatorConfig::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.admins, [AccountId32([7; 32])]);
+        assert!(matches!(invalid, Err(SimpletError::OperatorConfig(_))));
+    }
+
+    #[test]
+    fn test_reject_unknown_simplet() {
+        let path = std::env::temp_dir().join(format!("simplets-{}.toml", std::process::id()));
+        std::fs::write(&path, "[simplets.unknown]\napillon_key = \"x\"\n").unwrap();
+
+        let result = OperatorConfig::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(SimpletError::OperatorConfig(_))));
+    }
+
+    #[test]
+    fn test_reject_caller_only_field() {
+        let path =
+            std::env::temp_dir().join(format!("simplets-caller-{}.toml", std::process::id()));
+        std::fs::write(&path, "[simplets.email_airdrop]\napp_url = \"http://x\"\n").unwrap();
+
+        let result = OperatorConfig::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let Err(SimpletError::OperatorConfig(reason)) = result else {
+            panic!("caller-only field was accepted");
+        };
+        assert!(reason.contains("app_url"));
+    }
+}
@@ -0,0 +1,75 @@
+use crate::error::SimpletError;
+use std::collections::HashSet;
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+
+/// Hands out the host ports app containers are published on
+///
+/// Ports are taken from the operator's range, skipping the ones already assigned to an
+/// instance and the ones some other process on the host is listening on.
+#[derive(Debug)]
+pub struct PortAllocator {
+    range: RangeInclusive<u16>,
+    in_use: HashSet<u16>,
+}
+
+impl PortAllocator {
+    /// An allocator over `range` where `in_use` are already assigned
+    pub fn new(range: RangeInclusive<u16>, in_use: impl IntoIterator<Item = u16>) -> Self {
+        Self {
+            range,
+            in_use: in_use.into_iter().collect(),
+        }
+    }
+
+    /// Assign the lowest free port of the range
+    pub fn allocate(&mut self) -> Result<u16, SimpletError> {
+        let port = self
+            .range
+            .clone()
+            .find(|port| !self.in_use.contains(port) && Self::is_bindable(*port))
+            .ok_or_else(|| {
+                SimpletError::QuotaExceeded(format!(
+                    "no free host port left in {}-{}",
+                    self.range.start(),
+                    self.range.end()
+                ))
+            })?;
+
+        self.in_use.insert(port);
+        Ok(port)
+    }
+
+    /// Make `port` available again once its instance is gone
+    pub fn release(&mut self, port: u16) {
+        self.in_use.remove(&port);
+    }
+
+    fn is_bindable(port: u16) -> bool {
+        TcpListener::bind(("0.0.0.0", port)).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_skips_assigned_ports_until_exhausted() {
+        // Hold a port so the allocator sees it taken by another process
+        let listener = TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let taken = listener.local_addr().unwrap().port();
+        let mut ports = PortAllocator::new(taken..=taken, []);
+        assert!(matches!(
+            ports.allocate(),
+            Err(SimpletError::QuotaExceeded(_))
+        ));
+        drop(listener);
+
+        let mut ports = PortAllocator::new(taken..=taken, [taken]);
+        assert!(ports.allocate().is_err());
+        ports.release(taken);
+        assert_eq!(ports.allocate().unwrap(), taken);
+        assert!(ports.allocate().is_err());
+    }
+}
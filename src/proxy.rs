@@ -0,0 +1,826 @@
+use crate::certificates::{ACME_RESOLVER, ACME_STORAGE};
+use crate::error::SimpletError;
+use crate::simplets::image::{self, RegistryAuth};
+use crate::simplets::{
+    is_not_found, Endpoint, APP_PORT, NETWORK_ROLE, ROLE_LABEL, SERVICE_ID_LABEL,
+};
+use gadget_sdk::docker::bollard;
+use gadget_sdk::subxt_core::ext::sp_core::bytes::to_hex;
+use gadget_sdk::subxt_core::ext::sp_core::keccak_256;
+use gadget_sdk::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Network the proxy container of service `service_id` publishes its ports from
+///
+/// Apps never join it: the proxy joins the network of each routed instance instead, so
+/// instances can't reach each other through it.
+pub fn proxy_network(service_id: u64) -> String {
+    format!("simplets-proxy-{}", service_id)
+}
+
+/// Name of the proxy container of service `service_id`
+fn proxy_container(service_id: u64) -> String {
+    format!("simplets-proxy-{}", service_id)
+}
+
+/// [`ROLE_LABEL`] value of the proxy container and network
+const PROXY_ROLE: &str = "proxy";
+/// Label holding the fingerprint of the settings the proxy container was created with
+const PROXY_CONFIG_LABEL: &str = "tangle.apillon.proxy-config";
+/// Traefik entrypoint plain HTTP is served on
+const HTTP_ENTRYPOINT: &str = "web";
+/// Traefik entrypoint HTTPS is served on
+const HTTPS_ENTRYPOINT: &str = "websecure";
+/// Directory certificates are mounted in inside the proxy container
+const CERTS_DIR: &str = "/certs";
+/// Directory of the dynamic configuration inside the proxy container
+const DYNAMIC_DIR: &str = "/etc/traefik/dynamic";
+/// Directory of the ACME storage inside the proxy container
+const ACME_DIR: &str = "/etc/traefik/acme";
+
+/// Reverse proxy routing `<instance>.<domain>` to app containers
+///
+/// The proxy is a Traefik container reading its routes from the labels of the app
+/// containers, so routes come and go with the containers themselves.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Domain instances are routed under, apps are published on host ports if unset
+    pub domain: Option<String>,
+    pub image: String,
+    /// Host port the proxy serves HTTP on
+    pub http_port: u16,
+    /// Host port the proxy serves HTTPS on, when TLS is enabled
+    pub https_port: u16,
+    /// Where certificates come from, apps are served over plain HTTP if unset
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            domain: None,
+            image: "traefik:v3.1".to_string(),
+            http_port: 80,
+            https_port: 443,
+            tls: None,
+        }
+    }
+}
+
+/// Where the proxy gets the certificates of routed host names from
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TlsConfig {
+    /// `<hostname>.crt` and `<hostname>.key` pairs the operator puts in `cert_dir`
+    Files { cert_dir: PathBuf },
+    /// A single certificate for `*.<domain>`
+    Wildcard {
+        cert_file: PathBuf,
+        key_file: PathBuf,
+    },
+    /// Certificates obtained and renewed by the proxy from an ACME CA
+    Acme {
+        email: String,
+        /// Directory URL of the CA, Let's Encrypt if unset
+        ca_server: Option<String>,
+        /// Extra CA certificates to trust, e.g. the root of a local Pebble server
+        ca_certificates: Option<PathBuf>,
+    },
+}
+
+impl ProxyConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.domain.is_some()
+    }
+
+    /// The endpoint of an app routed under `slug`, `None` if the proxy is disabled
+    pub fn endpoint(&self, slug: &str) -> Option<Endpoint> {
+        let hostname = format!("{}.{}", slug, self.domain.as_ref()?);
+        let public_url = self.url(&hostname);
+
+        Some(Endpoint {
+            host_port: None,
+            hostname: Some(hostname),
+            custom_domain: None,
+            tls: self.tls.is_some(),
+            cert_resolver: match self.tls {
+                Some(TlsConfig::Acme { .. }) => Some(ACME_RESOLVER.to_string()),
+                _ => None,
+            },
+            public_url,
+        })
+    }
+
+    /// Fail unless customer domains can be routed through the proxy
+    pub fn check_custom_domains(&self) -> Result<(), SimpletError> {
+        if !self.is_enabled() {
+            return Err(SimpletError::InvalidConfig(
+                "custom domains need the operator's reverse proxy".to_string(),
+            ));
+        }
+        // The proxy would fall back to its self-signed default certificate
+        if let Some(TlsConfig::Wildcard { .. }) = self.tls {
+            return Err(SimpletError::InvalidConfig(
+                "the operator's wildcard certificate does not cover custom domains".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fail unless the proxy has a certificate for the customer domain `domain`, or
+    /// obtains one itself
+    pub fn check_certificate(&self, domain: &str) -> Result<(), SimpletError> {
+        self.check_custom_domains()?;
+        if let Some(TlsConfig::Files { cert_dir }) = &self.tls {
+            let present = ["crt", "key"]
+                .iter()
+                .all(|ext| cert_dir.join(format!("{}.{}", domain, ext)).exists());
+            if !present {
+                return Err(SimpletError::InvalidConfig(format!(
+                    "the operator has no certificate for {} yet",
+                    domain
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// URL clients reach `hostname` under through the proxy
+    pub fn url(&self, hostname: &str) -> String {
+        match (&self.tls, self.http_port, self.https_port) {
+            (None, 80, _) => format!("http://{}", hostname),
+            (None, port, _) => format!("http://{}:{}", hostname, port),
+            (Some(_), _, 443) => format!("https://{}", hostname),
+            (Some(_), _, port) => format!("https://{}:{}", hostname, port),
+        }
+    }
+}
+
+/// Labels telling the proxy to route the host names of `endpoint` to the app container of
+/// `instance_id` on its instance `network`, `None` if the app is not routed
+pub fn routing_labels(
+    instance_id: &str,
+    network: &str,
+    endpoint: &Endpoint,
+) -> Option<HashMap<String, String>> {
+    let hostname = endpoint.hostname.as_ref()?;
+    let router = format!("traefik.http.routers.{}", instance_id);
+    let rule = std::iter::once(hostname)
+        .chain(&endpoint.custom_domain)
+        .map(|hostname| format!("Host(`{}`)", hostname))
+        .collect::<Vec<_>>()
+        .join(" || ");
+    let entrypoint = if endpoint.tls {
+        HTTPS_ENTRYPOINT
+    } else {
+        HTTP_ENTRYPOINT
+    };
+
+    let mut labels = HashMap::from([
+        ("traefik.enable".to_string(), "true".to_string()),
+        ("traefik.docker.network".to_string(), network.to_string()),
+        (format!("{}.rule", router), rule),
+        (format!("{}.entrypoints", router), entrypoint.to_string()),
+        (
+            format!(
+                "traefik.http.services.{}.loadbalancer.server.port",
+                instance_id
+            ),
+            APP_PORT.to_string(),
+        ),
+    ]);
+    if endpoint.tls {
+        labels.insert(format!("{}.tls", router), "true".to_string());
+    }
+    if let Some(resolver) = &endpoint.cert_resolver {
+        labels.insert(format!("{}.tls.certresolver", router), resolver.clone());
+    }
+    Some(labels)
+}
+
+/// `[[tls.certificates]]` entry of the Traefik dynamic configuration
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CertificateFiles {
+    cert_file: String,
+    key_file: String,
+}
+
+/// Container settings of the proxy, derived from the operator's [`ProxyConfig`]
+#[derive(Debug, Default, Serialize)]
+struct ProxySettings {
+    image: String,
+    cmd: Vec<String>,
+    binds: Vec<String>,
+    env: Vec<String>,
+    /// Container port to host port
+    ports: Vec<(u16, u16)>,
+}
+
+impl ProxySettings {
+    /// Build the settings, writing the files the proxy reads below `state_dir`
+    fn prepare(config: &ProxyConfig, state_dir: &Path) -> Result<Self, SimpletError> {
+        Self::build(config, state_dir).map_err(|e| SimpletError::Proxy(e.to_string()))
+    }
+
+    fn build(config: &ProxyConfig, state_dir: &Path) -> std::io::Result<Self> {
+        let state_dir = std::path::absolute(state_dir)?;
+        let mut settings = Self {
+            image: config.image.clone(),
+            cmd: vec![
+                "--providers.docker=true".to_string(),
+                "--providers.docker.exposedbydefault=false".to_string(),
+                format!("--entrypoints.{}.address=:80", HTTP_ENTRYPOINT),
+            ],
+            ports: vec![(80, config.http_port)],
+            ..Default::default()
+        };
+
+        let Some(tls) = &config.tls else {
+            return Ok(settings);
+        };
+        settings.ports.push((443, config.https_port));
+        settings.cmd.extend([
+            format!("--entrypoints.{}.address=:443", HTTPS_ENTRYPOINT),
+            format!(
+                "--entrypoints.{}.http.redirections.entrypoint.to={}",
+                HTTP_ENTRYPOINT, HTTPS_ENTRYPOINT
+            ),
+            format!(
+                "--entrypoints.{}.http.redirections.entrypoint.scheme=https",
+                HTTP_ENTRYPOINT
+            ),
+        ]);
+
+        let certificates = match tls {
+            TlsConfig::Files { cert_dir } => {
+                let cert_dir = std::path::absolute(cert_dir)?;
+                settings
+                    .binds
+                    .push(format!("{}:{}:ro", cert_dir.display(), CERTS_DIR));
+                certificate_pairs(&cert_dir)?
+            }
+            TlsConfig::Wildcard {
+                cert_file,
+                key_file,
+            } => {
+                let cert = format!("{}/wildcard.crt", CERTS_DIR);
+                let key = format!("{}/wildcard.key", CERTS_DIR);
+                settings.binds.extend([
+                    format!("{}:{}:ro", std::path::absolute(cert_file)?.display(), cert),
+                    format!("{}:{}:ro", std::path::absolute(key_file)?.display(), key),
+                ]);
+                vec![CertificateFiles {
+                    cert_file: cert,
+                    key_file: key,
+                }]
+            }
+            TlsConfig::Acme {
+                email,
+                ca_server,
+                ca_certificates,
+            } => {
+                let acme_dir = state_dir.join("acme");
+                std::fs::create_dir_all(&acme_dir)?;
+                settings
+                    .binds
+                    .push(format!("{}:{}", acme_dir.display(), ACME_DIR));
+
+                let resolver = format!("--certificatesresolvers.{}.acme", ACME_RESOLVER);
+                settings.cmd.extend([
+                    format!("{}.email={}", resolver, email),
+                    format!("{}.storage={}/{}", resolver, ACME_DIR, ACME_STORAGE),
+                    format!("{}.tlschallenge=true", resolver),
+                ]);
+                if let Some(ca_server) = ca_server {
+                    settings
+                        .cmd
+                        .push(format!("{}.caserver={}", resolver, ca_server));
+                }
+                if let Some(ca_certificates) = ca_certificates {
+                    let mounted = "/etc/traefik/ca-certificates.pem";
+                    settings.binds.push(format!(
+                        "{}:{}:ro",
+                        std::path::absolute(ca_certificates)?.display(),
+                        mounted
+                    ));
+                    settings
+                        .env
+                        .push(format!("LEGO_CA_CERTIFICATES={}", mounted));
+                }
+                return Ok(settings);
+            }
+        };
+
+        // Static certificates are handed to the proxy through its file provider
+        let dynamic_dir = state_dir.join("dynamic");
+        std::fs::create_dir_all(&dynamic_dir)?;
+        let dynamic = HashMap::from([("tls", HashMap::from([("certificates", certificates)]))]);
+        let dynamic = toml::to_string(&dynamic).map_err(std::io::Error::other)?;
+        std::fs::write(dynamic_dir.join("certificates.toml"), dynamic)?;
+
+        settings
+            .binds
+            .push(format!("{}:{}:ro", dynamic_dir.display(), DYNAMIC_DIR));
+        settings.cmd.extend([
+            format!("--providers.file.directory={}", DYNAMIC_DIR),
+            "--providers.file.watch=true".to_string(),
+        ]);
+        Ok(settings)
+    }
+
+    fn fingerprint(&self) -> String {
+        let serialized = serde_json::to_string(self).unwrap();
+        to_hex(&keccak_256(serialized.as_bytes())[..], false)
+    }
+}
+
+/// Every `<name>.crt` in `cert_dir` with a matching `<name>.key`, as mounted in the proxy
+fn certificate_pairs(cert_dir: &Path) -> std::io::Result<Vec<CertificateFiles>> {
+    let mut pairs = Vec::new();
+    for entry in std::fs::read_dir(cert_dir)? {
+        let path = entry?.path();
+        if !path.extension().is_some_and(|ext| ext == "crt") {
+            continue;
+        }
+
+        let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if !cert_dir.join(format!("{}.key", name)).exists() {
+            warn!("Ignoring certificate {} without a key", path.display());
+            continue;
+        }
+        pairs.push(CertificateFiles {
+            cert_file: format!("{}/{}.crt", CERTS_DIR, name),
+            key_file: format!("{}/{}.key", CERTS_DIR, name),
+        });
+    }
+
+    // Keep the generated file stable so it only changes with the certificates
+    pairs.sort_by(|a, b| a.cert_file.cmp(&b.cert_file));
+    Ok(pairs)
+}
+
+/// Start the proxy container of service `service_id` and its network unless they already run
+///
+/// Files the proxy needs, such as its ACME storage, are kept below `state_dir`. A proxy
+/// created with other settings than the current ones is replaced.
+pub async fn ensure_proxy(
+    docker: &bollard::Docker,
+    service_id: u64,
+    config: &ProxyConfig,
+    registries: &[RegistryAuth],
+    state_dir: &Path,
+) -> Result<(), SimpletError> {
+    let settings = ProxySettings::prepare(config, state_dir)?;
+    let fingerprint = settings.fingerprint();
+    let network = proxy_network(service_id);
+    let container = proxy_container(service_id);
+    let labels = HashMap::from([
+        (ROLE_LABEL.to_string(), PROXY_ROLE.to_string()),
+        (SERVICE_ID_LABEL.to_string(), service_id.to_string()),
+    ]);
+
+    match docker
+        .inspect_network(
+            &network,
+            None::<bollard::network::InspectNetworkOptions<String>>,
+        )
+        .await
+    {
+        Ok(_) => {}
+        Err(e) if is_not_found(&e) => {
+            let options = bollard::network::CreateNetworkOptions {
+                name: network.clone(),
+                driver: "bridge".to_string(),
+                labels: labels.clone(),
+                check_duplicate: true,
+                ..Default::default()
+            };
+            docker.create_network(options).await?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    match docker.inspect_container(&container, None).await {
+        Ok(existing) => {
+            let current = existing
+                .config
+                .and_then(|config| config.labels)
+                .and_then(|mut labels| labels.remove(PROXY_CONFIG_LABEL));
+            if current.as_deref() == Some(fingerprint.as_str()) {
+                let running = existing
+                    .state
+                    .and_then(|state| state.running)
+                    .unwrap_or_default();
+                if !running {
+                    docker
+                        .start_container(
+                            &container,
+                            None::<bollard::container::StartContainerOptions<String>>,
+                        )
+                        .await?;
+                }
+                return Ok(());
+            }
+
+            info!("Proxy settings changed, replacing the proxy container");
+            let options = bollard::container::RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            };
+            docker.remove_container(&container, Some(options)).await?;
+        }
+        Err(e) if is_not_found(&e) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    image::ensure_image(docker, &settings.image, registries).await?;
+
+    let mut binds = vec!["/var/run/docker.sock:/var/run/docker.sock:ro".to_string()];
+    binds.extend(settings.binds);
+    let ports = settings
+        .ports
+        .iter()
+        .map(|(container_port, host_port)| {
+            let binding = bollard::models::PortBinding {
+                host_ip: None,
+                host_port: Some(host_port.to_string()),
+            };
+            (format!("{}/tcp", container_port), Some(vec![binding]))
+        })
+        .collect::<HashMap<_, _>>();
+    let mut labels = labels;
+    labels.insert(PROXY_CONFIG_LABEL.to_string(), fingerprint);
+
+    let proxy_config = bollard::container::Config {
+        image: Some(settings.image),
+        cmd: Some(settings.cmd),
+        env: Some(settings.env),
+        labels: Some(labels),
+        exposed_ports: Some(
+            ports
+                .keys()
+                .map(|port| (port.clone(), HashMap::new()))
+                .collect(),
+        ),
+        host_config: Some(bollard::models::HostConfig {
+            binds: Some(binds),
+            port_bindings: Some(ports),
+            network_mode: Some(network),
+            restart_policy: Some(bollard::models::RestartPolicy {
+                name: Some(bollard::models::RestartPolicyNameEnum::UNLESS_STOPPED),
+                maximum_retry_count: None,
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let options = bollard::container::CreateContainerOptions {
+        name: container.as_str(),
+        platform: None,
+    };
+    docker.create_container(Some(options), proxy_config).await?;
+    docker
+        .start_container(
+            &container,
+            None::<bollard::container::StartContainerOptions<String>>,
+        )
+        .await?;
+
+    // A new proxy container has lost the instance networks its predecessor was attached to
+    let role = format!("{}={}", ROLE_LABEL, NETWORK_ROLE);
+    let service = format!("{}={}", SERVICE_ID_LABEL, service_id);
+    let options = bollard::network::ListNetworksOptions {
+        filters: HashMap::from([("label", vec![role.as_str(), service.as_str()])]),
+    };
+    for network in docker.list_networks(Some(options)).await? {
+        if let Some(name) = &network.name {
+            attach(docker, service_id, name).await?;
+        }
+    }
+
+    info!("Started reverse proxy");
+    Ok(())
+}
+
+/// Networks the proxy container of service `service_id` is connected to, by name
+async fn proxy_networks(
+    docker: &bollard::Docker,
+    service_id: u64,
+) -> Result<Vec<String>, SimpletError> {
+    let info = docker
+        .inspect_container(&proxy_container(service_id), None)
+        .await?;
+    Ok(info
+        .network_settings
+        .and_then(|settings| settings.networks)
+        .map(|networks| networks.into_keys().collect())
+        .unwrap_or_default())
+}
+
+/// Connect the proxy of service `service_id` to the instance network `network` so it can
+/// reach the app on it
+pub async fn attach(
+    docker: &bollard::Docker,
+    service_id: u64,
+    network: &str,
+) -> Result<(), SimpletError> {
+    if proxy_networks(docker, service_id)
+        .await?
+        .iter()
+        .any(|n| n == network)
+    {
+        return Ok(());
+    }
+
+    let container = proxy_container(service_id);
+    let options = bollard::network::ConnectNetworkOptions {
+        container: container.as_str(),
+        ..Default::default()
+    };
+    docker.connect_network(network, options).await?;
+    Ok(())
+}
+
+/// Disconnect the proxy of service `service_id` from the instance network `network` so the
+/// network can be removed
+///
+/// Does nothing if there is no proxy, or it is not connected to `network`.
+pub async fn detach(
+    docker: &bollard::Docker,
+    service_id: u64,
+    network: &str,
+) -> Result<(), SimpletError> {
+    let networks = match proxy_networks(docker, service_id).await {
+        Ok(networks) => networks,
+        Err(SimpletError::Docker(e)) if is_not_found(&e) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !networks.iter().any(|n| n == network) {
+        return Ok(());
+    }
+
+    let container = proxy_container(service_id);
+    let options = bollard::network::DisconnectNetworkOptions {
+        container: container.as_str(),
+        force: true,
+    };
+    docker.disconnect_network(network, options).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certificates::{find_certificate, CertificateInfo};
+
+    #[test]
+    fn test_endpoint_under_domain() {
+        let config = ProxyConfig {
+            domain: Some("simplets.example.com".to_string()),
+            ..Default::default()
+        };
+        let endpoint = config.endpoint("poa-0123456789ab").unwrap();
+        assert_eq!(
+            endpoint.public_url,
+            "http://poa-0123456789ab.simplets.example.com"
+        );
+        assert_eq!(endpoint.host_port, None);
+
+        let config = ProxyConfig {
+            tls: Some(TlsConfig::Acme {
+                email: "ops@example.com".to_string(),
+                ca_server: None,
+                ca_certificates: None,
+            }),
+            ..config
+        };
+        let endpoint = config.endpoint("poa-0123456789ab").unwrap();
+        assert_eq!(
+            endpoint.public_url,
+            "https://poa-0123456789ab.simplets.example.com"
+        );
+        let labels = routing_labels("poa", "poa-net", &endpoint).unwrap();
+        assert_eq!(labels["traefik.docker.network"], "poa-net");
+        assert_eq!(
+            labels["traefik.http.routers.poa.entrypoints"],
+            HTTPS_ENTRYPOINT
+        );
+        assert_eq!(
+            labels["traefik.http.routers.poa.tls.certresolver"],
+            ACME_RESOLVER
+        );
+
+        let endpoint = Endpoint {
+            custom_domain: Some("poap.myconf.xyz".to_string()),
+            ..endpoint
+        };
+        let labels = routing_labels("poa", "poa-net", &endpoint).unwrap();
+        assert_eq!(
+            labels["traefik.http.routers.poa.rule"],
+            "Host(`poa-0123456789ab.simplets.example.com`) || Host(`poap.myconf.xyz`)"
+        );
+
+        assert!(ProxyConfig::default()
+            .endpoint("poa-0123456789ab")
+            .is_none());
+    }
+
+    #[test]
+    fn test_custom_domains_need_a_certificate() {
+        let cert_dir = std::env::temp_dir().join(format!("simplets-certs-{}", std::process::id()));
+        std::fs::create_dir_all(&cert_dir).unwrap();
+        let config = |tls| ProxyConfig {
+            domain: Some("simplets.example.com".to_string()),
+            tls: Some(tls),
+            ..Default::default()
+        };
+
+        let wildcard = config(TlsConfig::Wildcard {
+            cert_file: cert_dir.join("wildcard.crt"),
+            key_file: cert_dir.join("wildcard.key"),
+        });
+        let files = config(TlsConfig::Files {
+            cert_dir: cert_dir.clone(),
+        });
+        let before = files.check_certificate("poap.myconf.xyz");
+        std::fs::write(cert_dir.join("poap.myconf.xyz.crt"), "").unwrap();
+        std::fs::write(cert_dir.join("poap.myconf.xyz.key"), "").unwrap();
+        let after = files.check_certificate("poap.myconf.xyz");
+        std::fs::remove_dir_all(&cert_dir).unwrap();
+
+        assert!(wildcard.check_custom_domains().is_err());
+        assert!(before.is_err());
+        assert!(after.is_ok());
+        assert!(ProxyConfig::default().check_custom_domains().is_err());
+    }
+
+    /// Service id of the proxy started by [`test_acme_certificate_from_pebble`]
+    const PEBBLE_SERVICE_ID: u64 = 65_000;
+    const PEBBLE_CONTAINER: &str = "simplets-pebble";
+    const PEBBLE_APP: &str = "simplets-pebble-app";
+    const PEBBLE_NETWORK: &str = "simplets-pebble-net";
+
+    /// Extract the single file of the tar archive Docker hands out container files in
+    fn untar_file(archive: &[u8]) -> Vec<u8> {
+        let size = std::str::from_utf8(&archive[124..136])
+            .unwrap()
+            .trim_matches(|c: char| c == '\0' || c == ' ');
+        let size = usize::from_str_radix(size, 8).unwrap();
+        archive[512..512 + size].to_vec()
+    }
+
+    /// Start a Pebble CA and a proxy trusting it, route an app through the proxy and
+    /// wait for the proxy to obtain its certificate
+    async fn obtain_certificate_from_pebble(
+        docker: &bollard::Docker,
+        state_dir: &Path,
+    ) -> Result<Option<CertificateInfo>, SimpletError> {
+        use gadget_sdk::futures::TryStreamExt;
+
+        let pebble_image = "ghcr.io/letsencrypt/pebble:latest";
+        image::ensure_image(docker, pebble_image, &[]).await?;
+        let pebble = bollard::container::Config {
+            image: Some(pebble_image.to_string()),
+            // Pebble signs without checking the challenges, the test domain doesn't resolve
+            env: Some(vec![
+                "PEBBLE_VA_ALWAYS_VALID=1".to_string(),
+                "PEBBLE_WFE_NONCEREJECT=0".to_string(),
+            ]),
+            ..Default::default()
+        };
+        let options = bollard::container::CreateContainerOptions {
+            name: PEBBLE_CONTAINER,
+            platform: None,
+        };
+        docker.create_container(Some(options), pebble).await?;
+
+        // The proxy has to trust the root Pebble's HTTPS certificate is signed with
+        let options = bollard::container::DownloadFromContainerOptions {
+            path: "/test/certs/pebble.minica.pem",
+        };
+        let archive = docker
+            .download_from_container(PEBBLE_CONTAINER, Some(options))
+            .map_ok(|bytes| bytes.to_vec())
+            .try_concat()
+            .await?;
+        std::fs::create_dir_all(state_dir)?;
+        let ca_certificates = state_dir.join("pebble.minica.pem");
+        std::fs::write(&ca_certificates, untar_file(&archive))?;
+
+        let tls = TlsConfig::Acme {
+            email: "ops@example.com".to_string(),
+            ca_server: Some("https://pebble:14000/dir".to_string()),
+            ca_certificates: Some(ca_certificates),
+        };
+        let config = ProxyConfig {
+            domain: Some("simplets.test".to_string()),
+            // Any free host ports
+            http_port: 0,
+            https_port: 0,
+            tls: Some(tls.clone()),
+            ..Default::default()
+        };
+        ensure_proxy(docker, PEBBLE_SERVICE_ID, &config, &[], state_dir).await?;
+
+        let options = bollard::network::ConnectNetworkOptions {
+            container: PEBBLE_CONTAINER,
+            endpoint_config: bollard::models::EndpointSettings {
+                aliases: Some(vec!["pebble".to_string()]),
+                ..Default::default()
+            },
+        };
+        docker
+            .connect_network(&proxy_network(PEBBLE_SERVICE_ID), options)
+            .await?;
+        docker
+            .start_container(
+                PEBBLE_CONTAINER,
+                None::<bollard::container::StartContainerOptions<String>>,
+            )
+            .await?;
+
+        // The proxy asks for a certificate as soon as it sees the route of the app
+        let options = bollard::network::CreateNetworkOptions {
+            name: PEBBLE_NETWORK,
+            driver: "bridge",
+            check_duplicate: true,
+            ..Default::default()
+        };
+        docker.create_network(options).await?;
+        attach(docker, PEBBLE_SERVICE_ID, PEBBLE_NETWORK).await?;
+
+        let app_image = "traefik/whoami:latest";
+        image::ensure_image(docker, app_image, &[]).await?;
+        let endpoint = config.endpoint("pebble").unwrap();
+        let app = bollard::container::Config {
+            image: Some(app_image.to_string()),
+            cmd: Some(vec!["--port".to_string(), APP_PORT.to_string()]),
+            labels: routing_labels("pebble", PEBBLE_NETWORK, &endpoint),
+            host_config: Some(bollard::models::HostConfig {
+                network_mode: Some(PEBBLE_NETWORK.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let options = bollard::container::CreateContainerOptions {
+            name: PEBBLE_APP,
+            platform: None,
+        };
+        docker.create_container(Some(options), app).await?;
+        docker
+            .start_container(
+                PEBBLE_APP,
+                None::<bollard::container::StartContainerOptions<String>>,
+            )
+            .await?;
+
+        let hostname = endpoint.hostname.unwrap();
+        let acme_dir = state_dir.join("acme");
+        for _ in 0..45 {
+            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+            if let Some(certificate) = find_certificate(&tls, &acme_dir, &hostname)? {
+                return Ok(Some(certificate));
+            }
+        }
+        Ok(None)
+    }
+
+    #[tokio::test]
+    async fn test_acme_certificate_from_pebble() {
+        let Ok(docker) = gadget_sdk::docker::connect_to_docker(None).await else {
+            eprintln!("Skipping the ACME test, Docker is not available");
+            return;
+        };
+        let state_dir =
+            std::env::temp_dir().join(format!("simplets-pebble-{}", std::process::id()));
+
+        let obtained = obtain_certificate_from_pebble(&docker, &state_dir).await;
+
+        let options = bollard::container::RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+        for container in [
+            PEBBLE_APP.to_string(),
+            PEBBLE_CONTAINER.to_string(),
+            proxy_container(PEBBLE_SERVICE_ID),
+        ] {
+            let _ = docker.remove_container(&container, Some(options)).await;
+        }
+        for network in [PEBBLE_NETWORK.to_string(), proxy_network(PEBBLE_SERVICE_ID)] {
+            let _ = docker.remove_network(&network).await;
+        }
+        let _ = std::fs::remove_dir_all(&state_dir);
+
+        let certificate = obtained
+            .unwrap()
+            .expect("the proxy obtained no certificate");
+        assert_eq!(certificate.hostname, "pebble.simplets.test");
+    }
+}
@@ -0,0 +1,552 @@
+use crate::error::SimpletError;
+use crate::proxy;
+use crate::registry::{InstanceRecord, InstanceRegistry};
+use crate::simplets::{
+    ApillonSimpletsDocker, ImageRefs, ReadinessConfig, ServiceType, APP_ROLE, DB_ROLE,
+    INSTANCE_LABEL, RETIRED_APP_SUFFIX, ROLE_LABEL, SERVICE_ID_LABEL, SERVICE_LABEL,
+};
+use gadget_sdk::docker::bollard;
+use gadget_sdk::futures::future::join_all;
+use gadget_sdk::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// What [`reconcile`] changed to bring Docker and the registry back in line
+#[derive(Debug, Default)]
+pub struct ReconcileReport {
+    /// Known instances whose stopped containers were started again
+    pub restarted: Vec<String>,
+    /// Running instances that were missing from the registry
+    pub adopted: Vec<String>,
+    /// Instances whose leftover containers and networks were removed
+    pub removed: Vec<String>,
+    /// Registry entries dropped because their containers no longer exist
+    pub forgotten: Vec<String>,
+    /// Instances that could not be reconciled and are left as they were
+    pub failed: Vec<String>,
+}
+
+impl ReconcileReport {
+    fn fail(&mut self, instance_id: String, e: SimpletError) {
+        error!("Failed to reconcile simplet {}: {}", instance_id, e);
+        self.failed.push(instance_id);
+    }
+}
+
+/// What happened to an instance the registry knows about
+enum Known {
+    Forgotten,
+    Removed,
+    /// Its containers are there, and may need to be started
+    Present(ApillonSimpletsDocker),
+}
+
+/// The simplet containers Docker knows about for a single instance
+#[derive(Default)]
+struct InstanceContainers {
+    service_type: Option<ServiceType>,
+    db: Option<bollard::models::ContainerSummary>,
+    app: Option<bollard::models::ContainerSummary>,
+    /// App container an interrupted update was replacing
+    retired: Option<bollard::models::ContainerSummary>,
+}
+
+impl InstanceContainers {
+    fn ids(&self) -> impl Iterator<Item = &str> {
+        [&self.app, &self.db, &self.retired]
+            .into_iter()
+            .flatten()
+            .filter_map(|c| c.id.as_deref())
+    }
+
+    fn app_running(&self) -> bool {
+        self.app
+            .as_ref()
+            .and_then(|c| c.state.as_deref())
+            .is_some_and(|state| state == "running")
+    }
+}
+
+/// Match the instances in `registry` against the containers Docker is running for the
+/// service instance `service_id` of this blueprint
+///
+/// * Known instances with stopped containers are restarted.
+/// * Running instances missing from the registry are adopted.
+/// * Leftovers of failed deploys (e.g. a MySQL container without its app) are removed.
+/// * Registry entries without any containers are forgotten.
+///
+/// An instance that fails any of these steps is logged and reported in
+/// [`ReconcileReport::failed`], only failing to list the containers is an error. Resources
+/// of other services sharing the Docker host are left alone.
+pub async fn reconcile(
+    docker: Arc<bollard::Docker>,
+    registry: &mut InstanceRegistry,
+    service_id: u64,
+    readiness: ReadinessConfig,
+) -> Result<ReconcileReport, SimpletError> {
+    let mut report = ReconcileReport::default();
+    let mut found = list_instance_containers(&docker, service_id).await?;
+    for (instance_id, containers) in &mut found {
+        settle_retired_app(&docker, instance_id, containers).await;
+    }
+
+    let known = registry
+        .instances()
+        .map(|record| record.instance_id.clone())
+        .collect::<Vec<_>>();
+    let mut present = Vec::new();
+    for instance_id in known {
+        let containers = found.remove(&instance_id);
+        match reconcile_known(&docker, registry, &instance_id, containers).await {
+            Ok(Known::Forgotten) => report.forgotten.push(instance_id),
+            Ok(Known::Removed) => report.removed.push(instance_id),
+            Ok(Known::Present(service)) => present.push(
+                service
+                    .with_service_id(service_id)
+                    .with_readiness(readiness),
+            ),
+            Err(e) => report.fail(instance_id, e),
+        }
+    }
+
+    // Instances are resumed together so one that is slow to become healthy doesn't hold up
+    // the others
+    let resumed = join_all(present.iter().map(|service| service.resume())).await;
+    for (service, result) in present.iter().zip(resumed) {
+        let instance_id = service.instance_id().to_string();
+        match result {
+            Ok(true) => {
+                info!("Restarted simplet {}", instance_id);
+                report.restarted.push(instance_id);
+            }
+            Ok(false) => {}
+            Err(e) => report.fail(instance_id, e),
+        }
+    }
+
+    // Whatever is left is unknown to the registry
+    for (instance_id, containers) in found {
+        match containers.service_type {
+            Some(service_type) if containers.db.is_some() && containers.app_running() => {
+                let adopted = adopt(&docker, &instance_id, service_type, &containers)
+                    .await
+                    .and_then(|record| Ok(registry.upsert(record)?));
+                match adopted {
+                    Ok(()) => {
+                        info!("Adopted running simplet {}", instance_id);
+                        report.adopted.push(instance_id);
+                    }
+                    Err(e) => report.fail(instance_id, e),
+                }
+            }
+            _ => {
+                warn!("Removing orphaned simplet containers of {}", instance_id);
+                match remove_containers(&docker, &containers).await {
+                    Ok(()) => report.removed.push(instance_id),
+                    Err(e) => report.fail(instance_id, e),
+                }
+            }
+        }
+    }
+
+    remove_orphaned_networks(&docker, registry, service_id).await?;
+
+    Ok(report)
+}
+
+/// Bring a registered instance in line with the containers found for it
+async fn reconcile_known(
+    docker: &Arc<bollard::Docker>,
+    registry: &mut InstanceRegistry,
+    instance_id: &str,
+    containers: Option<InstanceContainers>,
+) -> Result<Known, SimpletError> {
+    let Some(containers) = containers else {
+        warn!(
+            "Containers of simplet {} are gone, forgetting it",
+            instance_id
+        );
+        registry.remove(instance_id)?;
+        return Ok(Known::Forgotten);
+    };
+
+    if containers.db.is_none() || containers.app.is_none() {
+        warn!("Simplet {} is incomplete, removing it", instance_id);
+        remove_containers(docker, &containers).await?;
+        registry.remove(instance_id)?;
+        return Ok(Known::Removed);
+    }
+
+    // Container ids may have changed if the registry was written mid-deploy
+    let mut record = registry
+        .get(instance_id)
+        .cloned()
+        .expect("record was listed by the caller");
+    record.db_container_id = containers.db.as_ref().and_then(|c| c.id.clone());
+    record.app_container_id = containers.app.as_ref().and_then(|c| c.id.clone());
+    registry.upsert(record.clone())?;
+
+    Ok(Known::Present(ApillonSimpletsDocker::from_record(
+        docker.clone(),
+        &record,
+    )))
+}
+
+/// Label filter matching the resources of the service instance `service_id`
+fn service_filter(service_id: u64) -> String {
+    format!("{}={}", SERVICE_ID_LABEL, service_id)
+}
+
+async fn list_instance_containers(
+    docker: &bollard::Docker,
+    service_id: u64,
+) -> Result<HashMap<String, InstanceContainers>, SimpletError> {
+    let service = service_filter(service_id);
+    let options = bollard::container::ListContainersOptions {
+        all: true,
+        filters: HashMap::from([("label", vec![INSTANCE_LABEL, service.as_str()])]),
+        ..Default::default()
+    };
+
+    let mut found: HashMap<String, InstanceContainers> = HashMap::new();
+    for container in docker.list_containers(Some(options)).await? {
+        let labels = container.labels.clone().unwrap_or_default();
+        let Some(instance_id) = labels.get(INSTANCE_LABEL) else {
+            continue;
+        };
+
+        let entry = found.entry(instance_id.clone()).or_default();
+        entry.service_type = labels
+            .get(SERVICE_LABEL)
+            .and_then(|key| ServiceType::from_key(key));
+        let retired_name = format!("/{}-{}", instance_id, RETIRED_APP_SUFFIX);
+        let retired = container
+            .names
+            .as_ref()
+            .is_some_and(|names| names.contains(&retired_name));
+        match labels.get(ROLE_LABEL).map(String::as_str) {
+            Some(DB_ROLE) => entry.db = Some(container),
+            Some(APP_ROLE) if retired => entry.retired = Some(container),
+            Some(APP_ROLE) => entry.app = Some(container),
+            _ => warn!("Ignoring container of {} with an unknown role", instance_id),
+        }
+    }
+
+    Ok(found)
+}
+
+/// Finish an app update that was interrupted by a crash
+///
+/// The retired app container is removed if its replacement exists, and takes the app's
+/// place again otherwise.
+async fn settle_retired_app(
+    docker: &bollard::Docker,
+    instance_id: &str,
+    containers: &mut InstanceContainers,
+) {
+    let Some(retired) = containers.retired.take() else {
+        return;
+    };
+    let Some(id) = retired.id.clone() else {
+        return;
+    };
+
+    if containers.app.is_some() {
+        warn!("Removing the replaced app container of {}", instance_id);
+        let options = bollard::container::RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+        if let Err(e) = docker.remove_container(&id, Some(options)).await {
+            warn!("Failed to remove container {}: {}", id, e);
+            containers.retired = Some(retired);
+        }
+        return;
+    }
+
+    warn!("Restoring the app container of {}", instance_id);
+    let options = bollard::container::RenameContainerOptions {
+        name: format!("{}-{}", instance_id, APP_ROLE),
+    };
+    if let Err(e) = docker.rename_container(&id, options).await {
+        warn!("Failed to rename container {}: {}", id, e);
+    }
+    containers.app = Some(retired);
+}
+
+/// Build a registry record for a running instance from its containers
+async fn adopt(
+    docker: &bollard::Docker,
+    instance_id: &str,
+    service_type: ServiceType,
+    containers: &InstanceContainers,
+) -> Result<InstanceRecord, SimpletError> {
+    let app = containers.app.as_ref().expect("app container is running");
+    let app_id = app.id.clone().unwrap_or_default();
+
+    // The app environment is a superset of the variables the instance was deployed with
+    let info = docker.inspect_container(&app_id, None).await?;
+    let env_vars = info
+        .config
+        .and_then(|config| config.env)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|var| {
+            var.split_once('=')
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+        })
+        .collect();
+    let network_id = app
+        .host_config
+        .as_ref()
+        .and_then(|config| config.network_mode.clone());
+    let images = match (
+        &app.image,
+        containers.db.as_ref().and_then(|c| c.image.clone()),
+    ) {
+        (Some(app_image), Some(db_image)) => Some(ImageRefs {
+            app: app_image.clone(),
+            db: db_image,
+        }),
+        _ => None,
+    };
+
+    Ok(InstanceRecord {
+        instance_id: instance_id.to_string(),
+        service_type,
+        // The config an adopted instance was deployed with is unknown
+        config_hash: String::new(),
+        env_vars,
+        network_id,
+        db_container_id: containers.db.as_ref().and_then(|c| c.id.clone()),
+        app_container_id: Some(app_id),
+        created_at: app.created.unwrap_or_default() as u64,
+        created_at_block: None,
+        owner: None,
+        images,
+        // The public URL is only known to the deploy that published the app
+        endpoint: None,
+        certificates: Vec::new(),
+        pending_domain: None,
+        // Containers are recreated with the baseline limits, whatever they ran with before
+        limits: None,
+    })
+}
+
+async fn remove_containers(
+    docker: &bollard::Docker,
+    containers: &InstanceContainers,
+) -> Result<(), SimpletError> {
+    let options = bollard::container::RemoveContainerOptions {
+        force: true,
+        ..Default::default()
+    };
+    for id in containers.ids() {
+        docker.remove_container(id, Some(options)).await?;
+    }
+    Ok(())
+}
+
+/// Remove simplet networks that no registered instance uses anymore
+async fn remove_orphaned_networks(
+    docker: &bollard::Docker,
+    registry: &InstanceRegistry,
+    service_id: u64,
+) -> Result<(), SimpletError> {
+    let known = registry
+        .instances()
+        .map(|record| record.instance_id.as_str())
+        .collect::<HashSet<_>>();
+
+    let service = service_filter(service_id);
+    let options = bollard::network::ListNetworksOptions {
+        filters: HashMap::from([("label", vec![INSTANCE_LABEL, service.as_str()])]),
+    };
+    for network in docker.list_networks(Some(options)).await? {
+        let instance_id = network
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.get(INSTANCE_LABEL));
+        if instance_id.is_some_and(|id| known.contains(id.as_str())) {
+            continue;
+        }
+
+        if let Some(id) = &network.id {
+            warn!("Removing orphaned simplet network {}", id);
+            if let Some(name) = &network.name {
+                if let Err(e) = proxy::detach(docker, service_id, name).await {
+                    warn!("Failed to detach the proxy from network {}: {}", id, e);
+                }
+            }
+            // A network still in use is left for the next start rather than failing this one
+            if let Err(e) = docker.remove_network(id).await {
+                warn!("Failed to remove simplet network {}: {}", id, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simplets::testing::{fake_docker, Route};
+    use serde_json::json;
+
+    const SERVICE_ID: u64 = 7;
+
+    fn container(id: &str, instance_id: &str, role: &str, name: &str) -> serde_json::Value {
+        json!({
+            "Id": id,
+            "Names": [format!("/{}", name)],
+            "Image": format!("{}-image", role),
+            "State": "running",
+            "Created": 1700000000,
+            "HostConfig": { "NetworkMode": format!("{}-net", instance_id) },
+            "Labels": {
+                INSTANCE_LABEL: instance_id,
+                SERVICE_LABEL: "email_airdrop",
+                ROLE_LABEL: role,
+                SERVICE_ID_LABEL: SERVICE_ID.to_string(),
+            },
+        })
+    }
+
+    fn running() -> serde_json::Value {
+        json!({ "State": { "Running": true } })
+    }
+
+    fn record(instance_id: &str, db: &str, app: &str) -> InstanceRecord {
+        InstanceRecord {
+            instance_id: instance_id.to_string(),
+            service_type: ServiceType::EmailAirdrop,
+            config_hash: "abcd".to_string(),
+            env_vars: HashMap::new(),
+            network_id: Some(format!("{}-net", instance_id)),
+            db_container_id: Some(db.to_string()),
+            app_container_id: Some(app.to_string()),
+            created_at: 1,
+            created_at_block: None,
+            owner: None,
+            images: None,
+            endpoint: None,
+            certificates: Vec::new(),
+            pending_domain: None,
+            limits: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_decisions() {
+        let containers = json!([
+            // Known and complete, with the app an interrupted update replaced
+            container("k-db", "known", DB_ROLE, "known-mysql"),
+            container("k-app", "known", APP_ROLE, "known-app"),
+            container("k-old", "known", APP_ROLE, "known-app-retired"),
+            // Known, crashed while its app was being replaced
+            container("s-db", "settled", DB_ROLE, "settled-mysql"),
+            container("s-old", "settled", APP_ROLE, "settled-app-retired"),
+            // Known, but its deploy never got to the app
+            container("i-db", "incomplete", DB_ROLE, "incomplete-mysql"),
+            // Unknown and running
+            container("r-db", "running", DB_ROLE, "running-mysql"),
+            container("r-app", "running", APP_ROLE, "running-app"),
+            // Unknown, left behind by a failed deploy
+            container("o-db", "orphan", DB_ROLE, "orphan-mysql"),
+        ]);
+        let networks = json!([
+            { "Id": "k-net", "Name": "known-net", "Labels": { INSTANCE_LABEL: "known" } },
+            { "Id": "o-net", "Name": "orphan-net", "Labels": { INSTANCE_LABEL: "orphan" } },
+        ]);
+        let (docker, requests) = fake_docker(vec![
+            Route::new("GET /containers/json", 200, containers),
+            Route::new("GET /networks?", 200, networks),
+            Route::new("GET /containers/k-db/json", 200, running()),
+            Route::new("GET /containers/k-app/json", 200, running()),
+            Route::new("GET /containers/s-db/json", 200, running()),
+            Route::new("GET /containers/s-old/json", 200, running()),
+            Route::new(
+                "GET /containers/r-app/json",
+                200,
+                json!({ "Config": { "Env": ["APP_URL=http://running", "COLLECTION_UUID=c"] } }),
+            ),
+            Route::not_found("GET /containers/simplets-proxy-7/json"),
+        ])
+        .await;
+
+        let path =
+            std::env::temp_dir().join(format!("simplets-reconcile-{}.json", std::process::id()));
+        let mut registry = InstanceRegistry::load(&path).unwrap();
+        for record in [
+            record("known", "k-db", "k-app"),
+            record("settled", "s-db", "s-app"),
+            record("incomplete", "i-db", "i-app"),
+            record("gone", "g-db", "g-app"),
+        ] {
+            registry.upsert(record).unwrap();
+        }
+
+        let report = reconcile(
+            Arc::new(docker),
+            &mut registry,
+            SERVICE_ID,
+            ReadinessConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert!(report.restarted.is_empty());
+        assert_eq!(report.adopted, ["running"]);
+        assert_eq!(report.forgotten, ["gone"]);
+        let mut removed = report.removed.clone();
+        removed.sort();
+        assert_eq!(removed, ["incomplete", "orphan"]);
+        assert!(report.failed.is_empty());
+
+        let mut instances = registry
+            .instances()
+            .map(|record| record.instance_id.as_str())
+            .collect::<Vec<_>>();
+        instances.sort();
+        assert_eq!(instances, ["known", "running", "settled"]);
+        let settled = registry.get("settled").unwrap();
+        assert_eq!(settled.app_container_id.as_deref(), Some("s-old"));
+        let adopted = registry.get("running").unwrap();
+        assert_eq!(adopted.app_url(), "http://running");
+        assert_eq!(adopted.db_container_id.as_deref(), Some("r-db"));
+        assert_eq!(adopted.network_id.as_deref(), Some("running-net"));
+
+        let requests = requests.lock().unwrap();
+        let sent = |request: &str| requests.iter().any(|line| line.starts_with(request));
+        // Only this service's resources are looked at
+        let lists = requests
+            .iter()
+            .filter(|line| {
+                line.starts_with("GET /containers/json") || line.starts_with("GET /networks?")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lists.len(), 2);
+        assert!(
+            lists.iter().all(|line| line.contains("service-id%3D7")),
+            "{:?}",
+            lists
+        );
+        // The update of the known instance is finished, the settled one gets its app back
+        assert!(sent("DELETE /containers/k-old"));
+        assert!(sent("POST /containers/s-old/rename?name=settled-app"));
+        assert!(!sent("DELETE /containers/s-old"));
+        // Leftovers are removed, everything else is kept
+        assert!(sent("DELETE /containers/i-db"));
+        assert!(sent("DELETE /containers/o-db"));
+        assert!(sent("DELETE /networks/o-net"));
+        for kept in ["k-db", "k-app", "s-db", "r-db", "r-app"] {
+            assert!(!sent(&format!("DELETE /containers/{}", kept)), "{}", kept);
+        }
+        assert!(!sent("DELETE /networks/k-net"));
+        assert!(!requests
+            .iter()
+            .any(|line| line.starts_with("POST /containers/") && line.contains("/start")));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use crate::certificates::CertificateInfo;
+use crate::domains::DomainChallenge;
+use crate::simplets::limits::InstanceLimits;
+use crate::simplets::{self, ApillonSimpletsDocker, Endpoint, ImageRefs, ServiceType};
+use gadget_sdk::event_listener::tangle::AccountId32;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File name of the registry inside the gadget data directory
+pub const REGISTRY_FILE: &str = "simplets.json";
+
+/// Everything needed to find and manage a simplet instance after a restart
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InstanceRecord {
+    pub instance_id: String,
+    pub service_type: ServiceType,
+    pub config_hash: String,
+    pub env_vars: HashMap<String, String>,
+    pub network_id: Option<String>,
+    pub db_container_id: Option<String>,
+    pub app_container_id: Option<String>,
+    /// Seconds since the unix epoch
+    pub created_at: u64,
+    /// Block in which the deploy job was called
+    #[serde(default)]
+    pub created_at_block: Option<u32>,
+    /// Account that deployed the instance, stored as an SS58 address
+    pub owner: Option<AccountId32>,
+    /// Images the instance runs, the service type's defaults if unset
+    #[serde(default)]
+    pub images: Option<ImageRefs>,
+    /// Where the app is published, if it is
+    #[serde(default)]
+    pub endpoint: Option<Endpoint>,
+    /// Certificates last seen for the app's host names, if they are served over TLS
+    #[serde(default)]
+    pub certificates: Vec<CertificateInfo>,
+    /// Custom domain the owner asked for that is not verified yet
+    #[serde(default)]
+    pub pending_domain: Option<DomainChallenge>,
+    /// Resource limits of the containers, [`InstanceLimits::baseline`] if unset
+    #[serde(default)]
+    pub limits: Option<InstanceLimits>,
+}
+
+impl InstanceRecord {
+    pub fn new(service: &ApillonSimpletsDocker, config_hash: impl Into<String>) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        Self {
+            instance_id: service.instance_id().to_string(),
+            service_type: service.service_type(),
+            config_hash: config_hash.into(),
+            env_vars: service.env_vars().clone(),
+            network_id: service.network_id().map(ToString::to_string),
+            db_container_id: service.db_container_id().map(ToString::to_string),
+            app_container_id: service.app_container_id().map(ToString::to_string),
+            created_at,
+            created_at_block: None,
+            owner: None,
+            images: Some(service.images().clone()),
+            endpoint: service.endpoint().cloned(),
+            certificates: Vec::new(),
+            pending_domain: None,
+            limits: Some(service.limits().clone()),
+        }
+    }
+}
+
+impl InstanceRecord {
+    /// The URL the instance's app advertises itself under
+    pub fn app_url(&self) -> String {
+        simplets::app_url(&self.env_vars, self.endpoint.as_ref())
+    }
+
+    /// Whether `caller` may modify this instance, either as its owner or as an operator admin
+    pub fn is_managed_by(&self, caller: &AccountId32, admins: &[AccountId32]) -> bool {
+        self.owner.as_ref() == Some(caller) || admins.contains(caller)
+    }
+}
+
+/// Persistent store of the simplet instances hosted by this operator
+///
+/// The registry is a JSON file that is rewritten on every change, so the
+/// operator can pick up its instances again after a restart.
+#[derive(Debug)]
+pub struct InstanceRegistry {
+    path: PathBuf,
+    instances: HashMap<String, InstanceRecord>,
+}
+
+impl InstanceRegistry {
+    /// Load the registry at `path`, starting empty if it does not exist yet
+    pub fn load(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let instances = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self { path, instances })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, instance_id: &str) -> Option<&InstanceRecord> {
+        self.instances.get(instance_id)
+    }
+
+    pub fn instances(&self) -> impl Iterator<Item = &InstanceRecord> {
+        self.instances.values()
+    }
+
+    /// The instances deployed by `owner`
+    pub fn owned_by<'a>(
+        &'a self,
+        owner: &'a AccountId32,
+    ) -> impl Iterator<Item = &'a InstanceRecord> {
+        self.instances()
+            .filter(move |record| record.owner.as_ref() == Some(owner))
+    }
+
+    /// Insert or replace a record and persist the registry
+    pub fn upsert(&mut self, record: InstanceRecord) -> io::Result<()> {
+        self.instances.insert(record.instance_id.clone(), record);
+        self.persist()
+    }
+
+    /// Remove a record and persist the registry
+    pub fn remove(&mut self, instance_id: &str) -> io::Result<Option<InstanceRecord>> {
+        let record = self.instances.remove(instance_id);
+        if record.is_some() {
+            self.persist()?;
+        }
+        Ok(record)
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // Write to a temporary file first so a crash never leaves a truncated registry
+        let tmp = self.path.with_extension("json.tmp");
+        let mut file = private_file(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(&self.instances)?)?;
+        file.sync_all()?;
+        std::fs::rename(tmp, &self.path)
+    }
+}
+
+/// Open `path` for writing, readable by the operator only since records hold secrets
+fn private_file(path: &Path) -> io::Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        // The mode only applies to new files, so a leftover temporary file is fixed up too
+        let file = options.mode(0o600).open(path)?;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        Ok(file)
+    }
+    #[cfg(not(unix))]
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(instance_id: &str) -> InstanceRecord {
+        InstanceRecord {
+            instance_id: instance_id.to_string(),
+            service_type: ServiceType::EmailAirdrop,
+            config_hash: "abcd".to_string(),
+            env_vars: HashMap::from([("APP_URL".to_string(), "http://test".to_string())]),
+            network_id: Some("net".to_string()),
+            db_container_id: Some("db".to_string()),
+            app_container_id: Some("app".to_string()),
+            created_at: 1,
+            created_at_block: Some(10),
+            owner: None,
+            images: None,
+            endpoint: None,
+            certificates: Vec::new(),
+            pending_domain: None,
+            limits: None,
+        }
+    }
+
+    #[test]
+    fn test_registry_survives_reload() {
+        let dir = std::env::temp_dir().join(format!("simplets-registry-{}", std::process::id()));
+        let path = dir.join(REGISTRY_FILE);
+
+        let mut registry = InstanceRegistry::load(&path).unwrap();
+        registry.upsert(record("email_airdrop_1")).unwrap();
+        registry.upsert(record("email_airdrop_2")).unwrap();
+        registry.remove("email_airdrop_1").unwrap();
+
+        let reloaded = InstanceRegistry::load(&path).unwrap();
+        assert!(reloaded.get("email_airdrop_1").is_none());
+        let record = reloaded.get("email_airdrop_2").unwrap();
+        assert_eq!(record.service_type, ServiceType::EmailAirdrop);
+        assert_eq!(record.app_container_id.as_deref(), Some("app"));
+        assert_eq!(record.app_url(), "http://test");
+
+        // Records hold the instances' secrets
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_owned_by_filters_on_owner() {
+        let path = std::env::temp_dir().join(format!("simplets-owned-{}.json", std::process::id()));
+        let mut registry = InstanceRegistry::load(&path).unwrap();
+        let (alice, bob) = (AccountId32([1; 32]), AccountId32([2; 32]));
+        for (id, owner) in [("a", Some(&alice)), ("b", Some(&bob)), ("c", None)] {
+            let mut record = record(id);
+            record.owner = owner.cloned();
+            registry.upsert(record).unwrap();
+        }
+
+        let owned = registry
+            .owned_by(&alice)
+            .map(|record| record.instance_id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(owned, ["a"]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_only_owner_and_admins_manage_instance() {
+        let (admin, alice, bob) = (
+            AccountId32([0; 32]),
+            AccountId32([1; 32]),
+            AccountId32([2; 32]),
+        );
+        let admins = [admin.clone()];
+        let mut record = record("a");
+        assert!(!record.is_managed_by(&alice, &admins));
+        assert!(record.is_managed_by(&admin, &admins));
+
+        record.owner = Some(alice.clone());
+        assert!(record.is_managed_by(&alice, &admins));
+        assert!(!record.is_managed_by(&bob, &admins));
+    }
+}
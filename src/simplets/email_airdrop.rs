@@ -1,18 +1,36 @@
 use super::{
-    deploy_service, ApillonSimpletsDocker, CommonConfig, ServiceConfig, ServiceType,
-    SimpletsBuilder, SmtpConfig,
+    deploy_service, ApillonSimpletsDocker, CommonConfig, DeployOptions, InstanceIdentity,
+    ServiceConfig, ServiceType, SimpletsBuilder, SmtpConfig,
 };
-use gadget_sdk::docker::bollard;
+use crate::error::SimpletError;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct EmailAirdropConfig {
-    common: CommonConfig,
-    collection_uuid: Option<String>,
+    #[serde(flatten)]
+    pub common: CommonConfig,
+    /// The Apillon NFT collection the airdrop distributes
+    pub collection_uuid: Option<String>,
 }
 
 impl ServiceConfig for EmailAirdropConfig {
+    fn common(&self) -> &CommonConfig {
+        &self.common
+    }
+
+    fn common_mut(&mut self) -> &mut CommonConfig {
+        &mut self.common
+    }
+
+    fn validate(&self) -> Result<(), SimpletError> {
+        require_collection_uuid(self.collection_uuid.as_deref())
+    }
+
+    fn validate_env_vars(env_vars: &HashMap<String, String>) -> Result<(), SimpletError> {
+        require_collection_uuid(env_vars.get("COLLECTION_UUID").map(String::as_str))
+    }
+
     fn into_env_vars(self) -> HashMap<String, String> {
         let mut env_vars = self.common.build_env_vars();
         if let Some(uuid) = self.collection_uuid {
@@ -22,6 +40,15 @@ impl ServiceConfig for EmailAirdropConfig {
     }
 }
 
+fn require_collection_uuid(uuid: Option<&str>) -> Result<(), SimpletError> {
+    match uuid {
+        Some(uuid) if !uuid.trim().is_empty() => Ok(()),
+        _ => Err(SimpletError::InvalidConfig(
+            "email airdrop requires a `collection_uuid`".to_string(),
+        )),
+    }
+}
+
 pub struct EmailAirdropBuilder {
     config: EmailAirdropConfig,
 }
@@ -30,10 +57,15 @@ pub struct EmailAirdropBuilder {
 impl SimpletsBuilder for EmailAirdropBuilder {
     type Config = EmailAirdropConfig;
 
+    const SERVICE_TYPE: ServiceType = ServiceType::EmailAirdrop;
+
     fn new() -> Self {
         Self {
             config: EmailAirdropConfig {
                 common: CommonConfig {
+                    name: None,
+                    custom_domain: None,
+                    tier: None,
                     app_secret: None,
                     app_url: None,
                     mysql_password: None,
@@ -48,6 +80,10 @@ impl SimpletsBuilder for EmailAirdropBuilder {
         }
     }
 
+    fn from_config(config: Self::Config) -> Self {
+        Self { config }
+    }
+
     fn get_config(&self) -> &Self::Config {
         &self.config
     }
@@ -92,8 +128,12 @@ impl SimpletsBuilder for EmailAirdropBuilder {
         self
     }
 
-    async fn deploy(self) -> Result<ApillonSimpletsDocker, bollard::errors::Error> {
-        deploy_service(self.config, ServiceType::EmailAirdrop).await
+    async fn deploy(
+        self,
+        identity: &InstanceIdentity,
+        options: &DeployOptions,
+    ) -> Result<ApillonSimpletsDocker, SimpletError> {
+        deploy_service(self.config, Self::SERVICE_TYPE, identity, options).await
     }
 }
 
@@ -127,9 +167,54 @@ mod tests {
                 email_from: "test@test.com".to_string(),
                 name_from: "Test Sender".to_string(),
             })
-            .deploy()
+            .deploy(
+                &InstanceIdentity {
+                    service_id: 0,
+                    owner: "test".to_string(),
+                    name: None,
+                },
+                &DeployOptions::default(),
+            )
             .await;
 
         assert!(airdrop.is_ok());
     }
+
+    #[test]
+    fn test_collection_uuid_from_caller_json() {
+        let config: EmailAirdropConfig = serde_json::from_str(
+            r#"{"app_url": "http://localhost:8080", "collection_uuid": "test-uuid"}"#,
+        )
+        .unwrap();
+
+        let env_vars = EmailAirdropBuilder::from_config(config)
+            .get_config()
+            .clone()
+            .into_env_vars();
+        assert_eq!(env_vars["APP_URL"], "http://localhost:8080");
+        assert_eq!(env_vars["COLLECTION_UUID"], "test-uuid");
+    }
+
+    #[test]
+    fn test_missing_collection_uuid_is_rejected() {
+        let config: EmailAirdropConfig =
+            serde_json::from_str(r#"{"app_url": "http://localhost:8080"}"#).unwrap();
+
+        assert!(matches!(
+            config.validate(),
+            Err(SimpletError::InvalidConfig(_))
+        ));
+
+        // Updates can't blank it on a deployed instance either
+        let mut env_vars = config.into_env_vars();
+        env_vars.insert("COLLECTION_UUID".to_string(), "test-uuid".to_string());
+        assert!(ServiceType::EmailAirdrop
+            .validate_env_vars(&env_vars)
+            .is_ok());
+        env_vars.insert("COLLECTION_UUID".to_string(), "".to_string());
+        assert!(matches!(
+            ServiceType::EmailAirdrop.validate_env_vars(&env_vars),
+            Err(SimpletError::InvalidConfig(_))
+        ));
+    }
 }
@@ -0,0 +1,123 @@
+use crate::error::SimpletError;
+use gadget_sdk::docker::bollard;
+use gadget_sdk::futures::StreamExt;
+use gadget_sdk::{info, warn};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+
+/// Registry every image without an explicit registry host is pulled from
+const DEFAULT_REGISTRY: &str = "docker.io";
+
+/// Credentials for a private registry images are pulled from
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegistryAuth {
+    /// Registry host, as it appears in image references (e.g. `ghcr.io`)
+    pub server: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for RegistryAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegistryAuth")
+            .field("server", &self.server)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Registry host an image reference is pulled from
+pub fn registry_host(image: &str) -> &str {
+    match image.split_once('/') {
+        // Only a first component that looks like a host names a registry
+        Some((host, _)) if host.contains(['.', ':']) || host == "localhost" => host,
+        _ => DEFAULT_REGISTRY,
+    }
+}
+
+/// Make sure `image` is present locally, pulling it if it is not
+///
+/// Credentials are used when `registries` has an entry for the image's registry.
+pub async fn ensure_image(
+    docker: &bollard::Docker,
+    image: &str,
+    registries: &[RegistryAuth],
+) -> Result<(), SimpletError> {
+    match docker.inspect_image(image).await {
+        Ok(_) => return Ok(()),
+        Err(e) if super::is_not_found(&e) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let host = registry_host(image);
+    let credentials = registries
+        .iter()
+        .find(|registry| registry.server == host)
+        .map(|registry| bollard::auth::DockerCredentials {
+            username: Some(registry.username.clone()),
+            password: Some(registry.password.clone()),
+            serveraddress: Some(registry.server.clone()),
+            ..Default::default()
+        });
+    let pull_error = |reason: String| SimpletError::ImagePull {
+        image: image.to_string(),
+        reason,
+    };
+
+    info!("Pulling image {}", image);
+    let options = bollard::image::CreateImageOptions {
+        from_image: image,
+        ..Default::default()
+    };
+    let mut progress = docker.create_image(Some(options), None, credentials);
+
+    // Layers report their progress many times per second, only log when a step changes
+    let mut layer_status = HashMap::new();
+    while let Some(update) = progress.next().await {
+        let update = update.map_err(|e| pull_error(e.to_string()))?;
+        if let Some(error) = update.error {
+            return Err(pull_error(error));
+        }
+
+        let (Some(layer), Some(status)) = (update.id, update.status) else {
+            continue;
+        };
+        if layer_status.get(&layer) != Some(&status) {
+            info!("{}: {} {}", image, layer, status);
+            layer_status.insert(layer, status);
+        }
+    }
+
+    info!("Pulled image {}", image);
+    Ok(())
+}
+
+/// Pull every image in `images` that is missing, logging failures instead of returning them
+///
+/// Run at startup so the first deploy does not spend its time pulling.
+pub async fn prefetch(
+    docker: &bollard::Docker,
+    images: impl IntoIterator<Item = String>,
+    registries: &[RegistryAuth],
+) {
+    for image in images.into_iter().collect::<BTreeSet<_>>() {
+        if let Err(e) = ensure_image(docker, &image, registries).await {
+            warn!("Failed to prefetch image {}: {}", image, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_host() {
+        assert_eq!(registry_host("mysql:8.4"), "docker.io");
+        assert_eq!(registry_host("apillon/ps-poa:1.0"), "docker.io");
+        assert_eq!(registry_host("ghcr.io/apillon/ps-poa:1.0"), "ghcr.io");
+        assert_eq!(registry_host("localhost:5000/ps-poa"), "localhost:5000");
+    }
+}
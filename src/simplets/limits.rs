@@ -0,0 +1,112 @@
+use gadget_sdk::docker::bollard::models::{HostConfig, ResourcesUlimits};
+use serde::{Deserialize, Serialize};
+
+const MIB: i64 = 1024 * 1024;
+
+/// Resource constraints of a single container, unset fields are unconstrained
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceLimits {
+    /// Memory the container may use in MiB, swap included
+    pub memory_mb: Option<i64>,
+    /// CPUs the container may use, e.g. `0.5` for half a core
+    pub cpus: Option<f64>,
+    /// Processes and threads the container may run
+    pub pids: Option<i64>,
+    /// File descriptors each process may open
+    pub nofile: Option<i64>,
+}
+
+impl ResourceLimits {
+    /// These limits with every field `overrides` sets replaced
+    pub fn overlay(&self, overrides: &Self) -> Self {
+        Self {
+            memory_mb: overrides.memory_mb.or(self.memory_mb),
+            cpus: overrides.cpus.or(self.cpus),
+            pids: overrides.pids.or(self.pids),
+            nofile: overrides.nofile.or(self.nofile),
+        }
+    }
+
+    /// Set the limits on the host config a container is created with
+    pub fn apply(&self, host_config: &mut HostConfig) {
+        if let Some(memory_mb) = self.memory_mb {
+            // Equal memory and swap limits keep the container from swapping
+            host_config.memory = Some(memory_mb * MIB);
+            host_config.memory_swap = Some(memory_mb * MIB);
+        }
+        if let Some(cpus) = self.cpus {
+            host_config.nano_cpus = Some((cpus * 1e9) as i64);
+        }
+        if let Some(pids) = self.pids {
+            host_config.pids_limit = Some(pids);
+        }
+        if let Some(nofile) = self.nofile {
+            host_config.ulimits = Some(vec![ResourcesUlimits {
+                name: Some("nofile".to_string()),
+                soft: Some(nofile),
+                hard: Some(nofile),
+            }]);
+        }
+    }
+}
+
+/// Resource constraints of both containers of an instance
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InstanceLimits {
+    pub app: ResourceLimits,
+    pub db: ResourceLimits,
+}
+
+impl InstanceLimits {
+    /// Limits every instance runs with unless the operator configures others
+    pub fn baseline() -> Self {
+        Self {
+            app: ResourceLimits {
+                memory_mb: Some(512),
+                cpus: Some(1.0),
+                pids: Some(256),
+                nofile: Some(4096),
+            },
+            db: ResourceLimits {
+                memory_mb: Some(1024),
+                cpus: Some(1.0),
+                pids: Some(512),
+                nofile: Some(8192),
+            },
+        }
+    }
+
+    /// These limits with every field `overrides` sets replaced
+    pub fn overlay(&self, overrides: &Self) -> Self {
+        Self {
+            app: self.app.overlay(&overrides.app),
+            db: self.db.overlay(&overrides.db),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlay_and_apply() {
+        let overrides = ResourceLimits {
+            memory_mb: Some(2048),
+            ..Default::default()
+        };
+        let limits = InstanceLimits::baseline().app.overlay(&overrides);
+        assert_eq!(limits.memory_mb, Some(2048));
+        assert_eq!(limits.pids, InstanceLimits::baseline().app.pids);
+
+        let mut host_config = HostConfig::default();
+        limits.apply(&mut host_config);
+        assert_eq!(host_config.memory, Some(2048 * MIB));
+        assert_eq!(host_config.memory_swap, host_config.memory);
+        assert_eq!(host_config.nano_cpus, Some(1_000_000_000));
+        assert_eq!(host_config.pids_limit, Some(256));
+        assert_eq!(host_config.ulimits.unwrap()[0].soft, Some(4096));
+    }
+}
@@ -1,19 +1,36 @@
-use gadget_sdk::docker::{bollard, connect_to_docker, Container};
+use crate::error::SimpletError;
+use crate::registry::InstanceRecord;
+use crate::{domains, proxy};
+use gadget_sdk::docker::{bollard, connect_to_docker};
 use gadget_sdk::subxt_core::ext::sp_core::bytes::to_hex;
 use gadget_sdk::subxt_core::ext::sp_core::keccak_256;
+use gadget_sdk::{info, warn};
+use image::RegistryAuth;
+use limits::InstanceLimits;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub mod email_airdrop;
+pub mod image;
+pub mod limits;
 pub mod proof_of_attendance;
+pub mod status;
+#[cfg(test)]
+pub(crate) mod testing;
 
 #[async_trait::async_trait]
-pub trait SimpletsBuilder {
-    type Config: Clone + Serialize;
+pub trait SimpletsBuilder: Sized {
+    /// The full config of this simplet, as callers send it as JSON
+    type Config: ServiceConfig + Clone + Serialize + DeserializeOwned;
+
+    /// The service this builder deploys
+    const SERVICE_TYPE: ServiceType;
 
     fn new() -> Self;
+    fn from_config(config: Self::Config) -> Self;
     fn app_secret(self, secret: impl Into<String>) -> Self;
     fn app_url(self, url: impl Into<String>) -> Self;
     fn mysql_password(self, password: impl Into<String>) -> Self;
@@ -24,15 +41,171 @@ pub trait SimpletsBuilder {
 
     fn get_config(&self) -> &Self::Config;
     fn get_config_mut(&mut self) -> &mut Self::Config;
-    fn get_unique_id(&self) -> String {
-        // Create a unique hash based on config values
-        let config = self.get_config();
-        let serialized = serde_json::to_string(config).unwrap();
-        let hash = keccak_256(serialized.as_bytes());
-        to_hex(&hash[..], false)
+    /// Fingerprint of the config, telling whether an instance runs with it
+    ///
+    /// Unlike the instance id, this changes whenever any config value does.
+    fn get_config_fingerprint(&self) -> String {
+        env_fingerprint(&self.get_config().clone().into_env_vars())
     }
 
-    async fn deploy(self) -> Result<ApillonSimpletsDocker, bollard::errors::Error>;
+    async fn deploy(
+        self,
+        identity: &InstanceIdentity,
+        options: &DeployOptions,
+    ) -> Result<ApillonSimpletsDocker, SimpletError>;
+}
+
+/// Fingerprint of the env vars an instance runs with, see
+/// [`SimpletsBuilder::get_config_fingerprint`]
+pub fn env_fingerprint(env_vars: &HashMap<String, String>) -> String {
+    let sorted: std::collections::BTreeMap<_, _> = env_vars.iter().collect();
+    let serialized = serde_json::to_string(&sorted).unwrap();
+    let hash = keccak_256(serialized.as_bytes());
+    to_hex(&hash[..], false)
+}
+
+/// A secret for the app of an instance whose caller did not choose one
+///
+/// Each instance gets its own, so tenants never share the key their app signs with.
+pub fn random_app_secret() -> String {
+    use gadget_sdk::random::RngCore;
+
+    let mut bytes = [0u8; 32];
+    gadget_sdk::random::getrandom_or_panic().fill_bytes(&mut bytes);
+    to_hex(&bytes, false)
+}
+
+/// Parse a caller's partial config into the env vars and custom domain it changes
+///
+/// Fields locked by the operator cannot be changed after deploy, and neither can the
+/// `name` and `tier` an instance was deployed with, so setting any of them is rejected
+/// rather than silently ignored. `smtp_config` may set any subset of
+/// its fields, the others keep the values the instance runs with.
+pub fn partial_update<C: ServiceConfig + DeserializeOwned>(
+    partial_config: &[u8],
+) -> Result<PartialUpdate, SimpletError> {
+    let mut partial = serde_json::from_slice::<serde_json::Value>(partial_config)
+        .map_err(|e| SimpletError::InvalidConfig(e.to_string()))?;
+    // Callers rarely know the SMTP password of the operator's relay, so they can't be asked
+    // for the whole block to change the sender name
+    let smtp_env_vars = match partial
+        .as_object_mut()
+        .and_then(|fields| fields.remove("smtp_config"))
+    {
+        Some(smtp) => partial_smtp_env_vars(smtp)?,
+        None => HashMap::new(),
+    };
+    let mut config = serde_json::from_value::<C>(partial)
+        .map_err(|e| SimpletError::InvalidConfig(e.to_string()))?;
+
+    let set = serde_json::to_value(config.common())
+        .map_err(|e| SimpletError::InvalidConfig(e.to_string()))?;
+    for (field, policy) in CommonConfig::POLICIES {
+        if *policy == MergePolicy::OperatorLocked && !set[field].is_null() {
+            return Err(SimpletError::InvalidConfig(format!(
+                "{} is set by the operator and cannot be updated",
+                field
+            )));
+        }
+    }
+    // Neither is an env var: the name is part of the instance id, the tier of its containers
+    for field in DEPLOY_ONLY_FIELDS {
+        if !set[field].is_null() {
+            return Err(SimpletError::InvalidConfig(format!(
+                "{} is chosen at deploy and cannot be updated",
+                field
+            )));
+        }
+    }
+
+    *config.common_mut() = CommonConfig::merge(&CommonConfig::default(), config.common());
+    let custom_domain = config
+        .common()
+        .custom_domain
+        .as_deref()
+        .map(domains::parse_domain)
+        .transpose()?;
+    let mut env_vars = config.into_env_vars();
+    env_vars.extend(smtp_env_vars);
+    if env_vars.is_empty() && custom_domain.is_none() {
+        return Err(SimpletError::InvalidConfig(
+            "the update does not change anything".to_string(),
+        ));
+    }
+    Ok(PartialUpdate {
+        env_vars,
+        custom_domain,
+    })
+}
+
+/// Caller fields [`partial_update`] rejects since only a new deploy can change them
+const DEPLOY_ONLY_FIELDS: [&str; 2] = ["name", "tier"];
+
+/// Fields of [`SmtpConfig`], each stored in the `SMTP_<FIELD>` env var
+const SMTP_FIELDS: [&str; 6] = [
+    "host",
+    "port",
+    "username",
+    "password",
+    "email_from",
+    "name_from",
+];
+
+/// Env vars of the [`SmtpConfig`] fields set in `smtp`
+fn partial_smtp_env_vars(smtp: serde_json::Value) -> Result<HashMap<String, String>, SimpletError> {
+    let invalid = |reason: String| SimpletError::InvalidConfig(format!("smtp_config: {}", reason));
+
+    let fields = match smtp {
+        serde_json::Value::Null => return Ok(HashMap::new()),
+        serde_json::Value::Object(fields) => fields,
+        _ => return Err(invalid("expected an object".to_string())),
+    };
+    fields
+        .into_iter()
+        .map(|(field, value)| {
+            if !SMTP_FIELDS.contains(&field.as_str()) {
+                return Err(invalid(format!("unknown field `{}`", field)));
+            }
+            match value {
+                serde_json::Value::String(value) => {
+                    Ok((format!("SMTP_{}", field.to_uppercase()), value))
+                }
+                _ => Err(invalid(format!("`{}` must be a string", field))),
+            }
+        })
+        .collect()
+}
+
+/// Changes a partial config makes to a running instance
+#[derive(Clone, Debug)]
+pub struct PartialUpdate {
+    pub env_vars: HashMap<String, String>,
+    /// Custom domain to route to the instance once its ownership is verified
+    pub custom_domain: Option<String>,
+}
+
+/// What an instance id is derived from, independently of the instance's config
+#[derive(Clone, Debug)]
+pub struct InstanceIdentity {
+    pub service_id: u64,
+    pub owner: String,
+    /// Name chosen by the caller, see [`Self::hash`]
+    pub name: Option<String>,
+}
+
+impl InstanceIdentity {
+    /// Hash the instance id is built from
+    ///
+    /// An instance keeps its id for as long as its owner redeploys it under the same name,
+    /// and each owner has one unnamed instance per service type, which is part of the id.
+    pub fn hash(&self) -> String {
+        let key = match &self.name {
+            Some(name) => format!("{}/owner/{}/{}", self.service_id, self.owner, name),
+            None => format!("{}/owner/{}", self.service_id, self.owner),
+        };
+        let hash = keccak_256(key.as_bytes());
+        to_hex(&hash[..], false)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -46,22 +219,102 @@ pub struct SmtpConfig {
 }
 
 pub trait ServiceConfig {
+    fn common(&self) -> &CommonConfig;
+    fn common_mut(&mut self) -> &mut CommonConfig;
     fn into_env_vars(self) -> HashMap<String, String>;
+
+    /// Reject configs missing fields the simplet cannot run without
+    fn validate(&self) -> Result<(), SimpletError> {
+        Ok(())
+    }
+
+    /// [`Self::validate`] for the env vars of a deployed instance, e.g. once an update
+    /// is merged into them
+    fn validate_env_vars(_env_vars: &HashMap<String, String>) -> Result<(), SimpletError>
+    where
+        Self: Sized,
+    {
+        Ok(())
+    }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct CommonConfig {
-    pub app_secret: Option<String>,
-    pub app_url: Option<String>,
-    pub mysql_password: Option<String>,
-    pub mysql_db: Option<String>,
-    pub admin_wallet: Option<String>,
-    pub apillon_key: Option<String>,
-    pub apillon_secret: Option<String>,
-    pub smtp_config: Option<SmtpConfig>,
+/// Who gets to decide the value of a [`CommonConfig`] field when both the operator
+/// and the caller provide one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Only the operator's value is used, callers cannot change it
+    OperatorLocked,
+    /// The caller's value wins, the operator's value is the default
+    CallerOverridable,
+    /// Only the caller's value is used, operator values are ignored
+    CallerOnly,
+}
+
+impl MergePolicy {
+    fn apply<T: Clone>(self, operator: &Option<T>, caller: &Option<T>) -> Option<T> {
+        match self {
+            MergePolicy::OperatorLocked => operator.clone(),
+            MergePolicy::CallerOverridable => caller.clone().or_else(|| operator.clone()),
+            MergePolicy::CallerOnly => caller.clone(),
+        }
+    }
+}
+
+/// Declares [`CommonConfig`] together with the [`MergePolicy`] of each field
+macro_rules! common_config {
+    ($($field:ident: $ty:ty => $policy:ident,)*) => {
+        #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+        pub struct CommonConfig {
+            $(pub $field: Option<$ty>,)*
+        }
+
+        impl CommonConfig {
+            /// The merge policy of every field, by field name
+            pub const POLICIES: &'static [(&'static str, MergePolicy)] =
+                &[$((stringify!($field), MergePolicy::$policy),)*];
+
+            /// Combine operator defaults with a caller's config according to [`Self::POLICIES`]
+            pub fn merge(operator: &Self, caller: &Self) -> Self {
+                Self {
+                    $($field: MergePolicy::$policy.apply(&operator.$field, &caller.$field),)*
+                }
+            }
+        }
+    };
+}
+
+common_config! {
+    name: String => CallerOnly,
+    custom_domain: String => CallerOnly,
+    tier: String => CallerOnly,
+    app_secret: String => CallerOnly,
+    app_url: String => CallerOnly,
+    mysql_password: String => OperatorLocked,
+    mysql_db: String => OperatorLocked,
+    admin_wallet: String => CallerOnly,
+    apillon_key: String => CallerOverridable,
+    apillon_secret: String => CallerOverridable,
+    smtp_config: SmtpConfig => CallerOverridable,
 }
 
 impl CommonConfig {
+    /// A copy that is safe to log, with every secret replaced
+    pub fn redacted(&self) -> Self {
+        const REDACTED: &str = "<redacted>";
+        let redact = |value: &Option<String>| value.as_ref().map(|_| REDACTED.to_string());
+
+        Self {
+            app_secret: redact(&self.app_secret),
+            mysql_password: redact(&self.mysql_password),
+            apillon_secret: redact(&self.apillon_secret),
+            smtp_config: self.smtp_config.clone().map(|smtp| SmtpConfig {
+                password: REDACTED.to_string(),
+                ..smtp
+            }),
+            ..self.clone()
+        }
+    }
+
     pub fn build_env_vars(&self) -> HashMap<String, String> {
         let mut env_vars = HashMap::new();
 
@@ -102,20 +355,228 @@ impl CommonConfig {
     }
 }
 
+/// Label attached to every Docker resource created for a simplet instance
+pub const INSTANCE_LABEL: &str = "tangle.apillon.instance";
+/// Label recording which [`ServiceType`] a resource belongs to
+pub const SERVICE_LABEL: &str = "tangle.apillon.service";
+/// Label recording the id of the service instance of this blueprint managing a resource,
+/// so several of them can share a Docker host
+pub const SERVICE_ID_LABEL: &str = "tangle.apillon.service-id";
+/// Label distinguishing the database and app containers of an instance
+pub const ROLE_LABEL: &str = "tangle.apillon.role";
+
+/// Label holding the fingerprint of the settings a container was created with
+const SPEC_LABEL: &str = "tangle.apillon.spec";
+
+/// [`ROLE_LABEL`] value of the MySQL container
+pub const DB_ROLE: &str = "mysql";
+/// [`ROLE_LABEL`] value of the app container
+pub const APP_ROLE: &str = "app";
+/// [`ROLE_LABEL`] value of the network of an instance
+pub const NETWORK_ROLE: &str = "net";
+/// Name suffix of an app container while it is being replaced
+///
+/// Docker can't change the labels of a container, so it keeps [`APP_ROLE`] and is told
+/// apart by its name.
+pub const RETIRED_APP_SUFFIX: &str = "app-retired";
+/// [`ROLE_LABEL`] value of the volume holding the MySQL data
+pub const DB_VOLUME_ROLE: &str = "mysql-data";
+/// [`ROLE_LABEL`] value of the volume holding the app data
+pub const APP_VOLUME_ROLE: &str = "app-data";
+
+/// What [`ApillonSimpletsDocker::cleanup`] does with the data volumes of an instance
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VolumePolicy {
+    /// Keep the volumes, a later deploy of the same instance id picks its data up again
+    Retain,
+    /// Delete the volumes and all data in them
+    Purge,
+}
+
+/// Controls how long [`ApillonSimpletsDocker`] waits for its containers to become healthy
+///
+/// Operators set both in seconds, as `timeout_secs` and `interval_secs`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadinessConfig {
+    /// Give up on the deployment if a container is not healthy after this long
+    #[serde(rename = "timeout_secs", deserialize_with = "duration_secs")]
+    pub timeout: Duration,
+    /// Delay between health probes
+    #[serde(rename = "interval_secs", deserialize_with = "duration_secs")]
+    pub interval: Duration,
+}
+
+fn duration_secs<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+impl Default for ReadinessConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(120),
+            interval: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Image references an instance runs, pinned when it is deployed
+///
+/// References may carry a digest (`repo@sha256:...`) for fully reproducible deploys.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageRefs {
+    pub app: String,
+    pub db: String,
+}
+
+impl ImageRefs {
+    /// Images used when the operator pins none
+    pub fn defaults(service_type: ServiceType) -> Self {
+        Self {
+            app: service_type.get_app_image().to_string(),
+            db: "mysql".to_string(),
+        }
+    }
+}
+
+/// `image` with its tag and digest replaced by `tag`, which may itself be a digest
+pub fn retag_image(image: &str, tag: &str) -> String {
+    let repository = image.split('@').next().unwrap_or(image);
+    let name_start = repository.rfind('/').map_or(0, |i| i + 1);
+    let repository = match repository[name_start..].find(':') {
+        Some(i) => &repository[..name_start + i],
+        None => repository,
+    };
+
+    if tag.contains(':') {
+        format!("{}@{}", repository, tag)
+    } else {
+        format!("{}:{}", repository, tag)
+    }
+}
+
+/// Port the app listens on inside its container
+pub const APP_PORT: u16 = 3000;
+
+/// Where an instance's app is published on the host
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Endpoint {
+    /// Host port [`APP_PORT`] is published on, unset when the app is routed by the proxy
+    #[serde(default)]
+    pub host_port: Option<u16>,
+    /// Host name the proxy routes to the app, unset when the app is published on a port
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Verified customer domain the proxy also routes to the app
+    #[serde(default)]
+    pub custom_domain: Option<String>,
+    /// Whether the proxy terminates TLS for the host names
+    #[serde(default)]
+    pub tls: bool,
+    /// Proxy certificate resolver obtaining the certificate, unset for static certificates
+    #[serde(default)]
+    pub cert_resolver: Option<String>,
+    /// URL clients reach the app under
+    pub public_url: String,
+}
+
+/// URL an app with `env_vars` advertises itself under when it is reachable at `endpoint`
+pub(crate) fn app_url(env_vars: &HashMap<String, String>, endpoint: Option<&Endpoint>) -> String {
+    env_vars
+        .get("APP_URL")
+        .cloned()
+        .or_else(|| endpoint.map(|e| e.public_url.clone()))
+        .unwrap_or_else(|| format!("http://localhost:{}", APP_PORT))
+}
+
+/// Operator settings applied when deploying an instance
+#[derive(Clone, Debug, Default)]
+pub struct DeployOptions {
+    /// Images to run, [`ImageRefs::defaults`] if unset
+    pub images: Option<ImageRefs>,
+    /// Credentials for the private registries images are pulled from
+    pub registries: Vec<RegistryAuth>,
+    /// Where to publish the app, unpublished if unset
+    pub endpoint: Option<Endpoint>,
+    /// Resource limits of the containers, [`InstanceLimits::baseline`] if unset
+    pub limits: Option<InstanceLimits>,
+    /// How long to wait for the containers to become healthy
+    pub readiness: ReadinessConfig,
+}
+
 #[derive(Clone)]
 pub struct ApillonSimpletsDocker {
     docker: Arc<bollard::Docker>,
+    /// Service instance of this blueprint the simplet belongs to
+    service_id: u64,
+    instance_id: String,
     env_vars: HashMap<String, String>,
     service_type: ServiceType,
+    images: ImageRefs,
+    registries: Vec<RegistryAuth>,
+    endpoint: Option<Endpoint>,
+    limits: InstanceLimits,
+    readiness: ReadinessConfig,
+    network_id: Option<String>,
+    db_container_id: Option<String>,
+    app_container_id: Option<String>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ServiceType {
     ProofOfAttendance,
     EmailAirdrop,
 }
 
 impl ServiceType {
+    /// The prefix used for instance ids of this service type
+    pub fn key(&self) -> &'static str {
+        match self {
+            ServiceType::ProofOfAttendance => "proof_of_attendance",
+            ServiceType::EmailAirdrop => "email_airdrop",
+        }
+    }
+
+    /// Inverse of [`Self::key`]
+    pub fn from_key(key: &str) -> Option<Self> {
+        match key {
+            "proof_of_attendance" => Some(ServiceType::ProofOfAttendance),
+            "email_airdrop" => Some(ServiceType::EmailAirdrop),
+            _ => None,
+        }
+    }
+
+    /// Build the instance id of a deployment from its [`InstanceIdentity::hash`]
+    pub fn instance_id(&self, identity_hash: &str) -> String {
+        format!("{}_{}", self.key(), identity_hash)
+    }
+
+    /// Short, DNS-safe label naming a deployment in the host names it is routed under
+    pub fn slug(&self, identity_hash: &str) -> String {
+        let short_name = match self {
+            ServiceType::ProofOfAttendance => "poa",
+            ServiceType::EmailAirdrop => "airdrop",
+        };
+        let hash = identity_hash.get(..12).unwrap_or(identity_hash);
+        format!("{}-{}", short_name, hash)
+    }
+
+    /// Reject env vars an instance of this service type cannot run with
+    pub fn validate_env_vars(
+        &self,
+        env_vars: &HashMap<String, String>,
+    ) -> Result<(), SimpletError> {
+        match self {
+            ServiceType::ProofOfAttendance => {
+                proof_of_attendance::ProofOfAttendanceConfig::validate_env_vars(env_vars)
+            }
+            ServiceType::EmailAirdrop => {
+                email_airdrop::EmailAirdropConfig::validate_env_vars(env_vars)
+            }
+        }
+    }
+
     fn get_db_name(&self) -> &'static str {
         match self {
             ServiceType::ProofOfAttendance => "poa_db",
@@ -134,17 +595,152 @@ impl ServiceType {
 impl ApillonSimpletsDocker {
     pub fn new(
         docker: Arc<bollard::Docker>,
+        instance_id: impl Into<String>,
         env_vars: HashMap<String, String>,
         service_type: ServiceType,
     ) -> Self {
         Self {
             docker,
+            service_id: 0,
+            instance_id: instance_id.into(),
             env_vars,
             service_type,
+            images: ImageRefs::defaults(service_type),
+            registries: Vec::new(),
+            endpoint: None,
+            limits: InstanceLimits::baseline(),
+            readiness: ReadinessConfig::default(),
+            network_id: None,
+            db_container_id: None,
+            app_container_id: None,
         }
     }
 
-    pub async fn start(&self) -> Result<(), bollard::errors::Error> {
+    /// Rebuild the handle of an instance deployed before the operator restarted
+    pub fn from_record(docker: Arc<bollard::Docker>, record: &InstanceRecord) -> Self {
+        Self {
+            docker,
+            service_id: 0,
+            instance_id: record.instance_id.clone(),
+            env_vars: record.env_vars.clone(),
+            service_type: record.service_type,
+            images: record
+                .images
+                .clone()
+                .unwrap_or_else(|| ImageRefs::defaults(record.service_type)),
+            registries: Vec::new(),
+            endpoint: record.endpoint.clone(),
+            limits: record
+                .limits
+                .clone()
+                .unwrap_or_else(InstanceLimits::baseline),
+            readiness: ReadinessConfig::default(),
+            network_id: record.network_id.clone(),
+            db_container_id: record.db_container_id.clone(),
+            app_container_id: record.app_container_id.clone(),
+        }
+    }
+
+    pub fn with_service_id(mut self, service_id: u64) -> Self {
+        self.service_id = service_id;
+        self
+    }
+
+    pub fn with_readiness(mut self, readiness: ReadinessConfig) -> Self {
+        self.readiness = readiness;
+        self
+    }
+
+    pub fn with_images(mut self, images: ImageRefs) -> Self {
+        self.images = images;
+        self
+    }
+
+    pub fn with_registries(mut self, registries: Vec<RegistryAuth>) -> Self {
+        self.registries = registries;
+        self
+    }
+
+    pub fn with_endpoint(mut self, endpoint: Endpoint) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    pub fn with_limits(mut self, limits: InstanceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    pub fn service_type(&self) -> ServiceType {
+        self.service_type
+    }
+
+    pub fn env_vars(&self) -> &HashMap<String, String> {
+        &self.env_vars
+    }
+
+    pub fn images(&self) -> &ImageRefs {
+        &self.images
+    }
+
+    pub fn endpoint(&self) -> Option<&Endpoint> {
+        self.endpoint.as_ref()
+    }
+
+    pub fn limits(&self) -> &InstanceLimits {
+        &self.limits
+    }
+
+    pub fn network_id(&self) -> Option<&str> {
+        self.network_id.as_deref()
+    }
+
+    pub fn db_container_id(&self) -> Option<&str> {
+        self.db_container_id.as_deref()
+    }
+
+    pub fn app_container_id(&self) -> Option<&str> {
+        self.app_container_id.as_deref()
+    }
+
+    /// The URL the app advertises itself under, its public URL unless the caller set one
+    pub fn app_url(&self) -> String {
+        app_url(&self.env_vars, self.endpoint.as_ref())
+    }
+
+    /// Name of the Docker resource serving `role` for this instance
+    fn resource_name(&self, role: &str) -> String {
+        format!("{}-{}", self.instance_id, role)
+    }
+
+    fn labels(&self, role: &str) -> HashMap<String, String> {
+        HashMap::from([
+            (SERVICE_ID_LABEL.to_string(), self.service_id.to_string()),
+            (INSTANCE_LABEL.to_string(), self.instance_id.clone()),
+            (
+                SERVICE_LABEL.to_string(),
+                self.service_type.key().to_string(),
+            ),
+            (ROLE_LABEL.to_string(), role.to_string()),
+        ])
+    }
+
+    pub async fn start(&mut self) -> Result<(), SimpletError> {
+        // Both containers share an isolated network so the app can resolve its database
+        self.network_id = Some(self.create_network().await?);
+
+        // Pull both images up front so a missing app image fails before MySQL starts
+        self.ensure_image(&self.images.db).await?;
+        self.ensure_image(&self.images.app).await?;
+
+        // Each instance keeps its data in its own volumes, never in a shared directory
+        let db_volume = self.create_volume(DB_VOLUME_ROLE).await?;
+        let app_volume = self.create_volume(APP_VOLUME_ROLE).await?;
+
         // Start MySQL container first
         let db_env = vec![
             format!(
@@ -158,78 +754,524 @@ impl ApillonSimpletsDocker {
                 self.env_vars.get("MYSQL_DB").unwrap_or(&"poa".to_string())
             ),
         ];
+        let mut db_host_config = bollard::models::HostConfig {
+            binds: Some(vec![format!("{}:/var/lib/mysql", db_volume)]),
+            ..Default::default()
+        };
+        self.limits.db.apply(&mut db_host_config);
+
+        let db_config = bollard::container::Config {
+            image: Some(self.images.db.clone()),
+            env: Some(db_env),
+            healthcheck: Some(self.mysql_healthcheck()),
+            host_config: Some(db_host_config),
+            ..Default::default()
+        };
+        let db_id = self
+            .create_and_start(DB_ROLE, self.service_type.get_db_name(), db_config)
+            .await?;
+        self.db_container_id = Some(db_id.clone());
+
+        // The app runs migrations on boot, so MySQL must accept connections first
+        self.wait_for_healthy(DB_ROLE, &db_id).await?;
 
-        let mut db_container = Container::new(&self.docker, "mysql");
-        db_container
-            .env(db_env)
-            .binds(["./mysql-data:/var/lib/mysql"]);
+        let app_id = self.start_app(&app_volume).await?;
+        self.app_container_id = Some(app_id.clone());
+        self.wait_for_healthy(APP_ROLE, &app_id).await?;
 
-        db_container.create().await?;
-        db_container.start(false).await?;
+        Ok(())
+    }
 
-        // Wait for MySQL to be healthy
-        self.wait_for_mysql(&db_container).await?;
+    async fn ensure_image(&self, image: &str) -> Result<(), SimpletError> {
+        image::ensure_image(&self.docker, image, &self.registries).await
+    }
 
-        // Start app container with updated configuration
+    /// Create and start the app container from the current env vars
+    async fn start_app(&self, volume: &str) -> Result<String, SimpletError> {
         let app_env = self.build_app_environment();
+        let app_volume = format!("{}:/app/data", volume);
+        let app_port = format!("{}/tcp", APP_PORT);
+        let endpoint = self.endpoint.as_ref();
+        let port_bindings = endpoint.and_then(|e| e.host_port).map(|host_port| {
+            let binding = bollard::models::PortBinding {
+                host_ip: None,
+                host_port: Some(host_port.to_string()),
+            };
+            HashMap::from([(app_port.clone(), Some(vec![binding]))])
+        });
+        let network = self.resource_name(NETWORK_ROLE);
+        let labels = endpoint.and_then(|e| proxy::routing_labels(&self.instance_id, &network, e));
+        let routed = labels.is_some();
+        let mut app_host_config = bollard::models::HostConfig {
+            binds: Some(vec![app_volume]),
+            port_bindings,
+            ..Default::default()
+        };
+        self.limits.app.apply(&mut app_host_config);
 
-        let mut app_container = Container::new(&self.docker, self.service_type.get_app_image());
-        app_container.env(app_env).binds(["./app-data:/app/data"]);
+        let app_config = bollard::container::Config {
+            image: Some(self.images.app.clone()),
+            env: Some(app_env),
+            exposed_ports: Some(HashMap::from([(app_port, HashMap::new())])),
+            labels,
+            healthcheck: Some(self.app_healthcheck()),
+            host_config: Some(app_host_config),
+            ..Default::default()
+        };
+        let app_id = self
+            .create_and_start(APP_ROLE, APP_ROLE, app_config)
+            .await?;
 
-        app_container.create().await?;
-        app_container.start(false).await?;
+        if routed {
+            proxy::attach(&self.docker, self.service_id, &network).await?;
+        }
+        Ok(app_id)
+    }
 
-        Ok(())
+    /// Replace the app container with one running on `env_vars`
+    ///
+    /// MySQL and both data volumes are left untouched.
+    pub async fn update_env(
+        &mut self,
+        env_vars: HashMap<String, String>,
+    ) -> Result<(), SimpletError> {
+        let image = self.images.app.clone();
+        self.replace_app(env_vars, image).await
+    }
+
+    /// Replace the app container with one the proxy also routes `domain` to, with `APP_URL`
+    /// set to `public_url`
+    pub async fn add_custom_domain(
+        &mut self,
+        domain: &str,
+        public_url: String,
+    ) -> Result<(), SimpletError> {
+        let previous = self
+            .endpoint
+            .clone()
+            .filter(|endpoint| endpoint.hostname.is_some())
+            .ok_or_else(|| {
+                SimpletError::InvalidConfig(format!(
+                    "simplet {} is not routed by the proxy",
+                    self.instance_id
+                ))
+            })?;
+
+        self.endpoint = Some(Endpoint {
+            custom_domain: Some(domain.to_string()),
+            public_url: public_url.clone(),
+            ..previous.clone()
+        });
+        let mut env_vars = self.env_vars.clone();
+        env_vars.insert("APP_URL".to_string(), public_url);
+        let result = self.update_env(env_vars).await;
+        if result.is_err() {
+            self.endpoint = Some(previous);
+        }
+        result
+    }
+
+    /// Replace the app container with one running `image`, keeping its data
+    pub async fn upgrade(&mut self, image: impl Into<String>) -> Result<(), SimpletError> {
+        let env_vars = self.env_vars.clone();
+        self.replace_app(env_vars, image.into()).await
+    }
+
+    /// Swap the app container for one built from `env_vars` and `image`
+    ///
+    /// The new container is started while the old one still runs, unless the old one holds
+    /// a published port, and the old one is restored if the new one fails to start or to
+    /// pass its health check.
+    async fn replace_app(
+        &mut self,
+        env_vars: HashMap<String, String>,
+        image: String,
+    ) -> Result<(), SimpletError> {
+        // Nothing has been touched yet if the new image cannot be pulled
+        self.ensure_image(&image).await?;
+
+        let force_options = bollard::container::RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+        let app_name = self.resource_name(APP_ROLE);
+        let retired_name = self.resource_name(RETIRED_APP_SUFFIX);
+
+        // A failed update may have left its retired container behind
+        match self
+            .docker
+            .remove_container(&retired_name, Some(force_options))
+            .await
+        {
+            Err(e) if !is_not_found(&e) => return Err(e.into()),
+            _ => {}
+        }
+
+        // Free the app name for the new container
+        let old_id = self.app_container_id.clone().unwrap_or(app_name.clone());
+        self.docker
+            .rename_container(
+                &old_id,
+                bollard::container::RenameContainerOptions { name: retired_name },
+            )
+            .await?;
+
+        // Only one container can hold the published port, so the old one must make way
+        if self
+            .endpoint
+            .as_ref()
+            .is_some_and(|e| e.host_port.is_some())
+        {
+            self.docker
+                .stop_container(&old_id, None::<bollard::container::StopContainerOptions>)
+                .await?;
+        }
+
+        let old_env = std::mem::replace(&mut self.env_vars, env_vars);
+        let old_image = std::mem::replace(&mut self.images.app, image);
+        let started = match self.start_app(&self.resource_name(APP_VOLUME_ROLE)).await {
+            Ok(new_id) => self
+                .wait_for_healthy(APP_ROLE, &new_id)
+                .await
+                .map(|()| new_id),
+            Err(e) => Err(e),
+        };
+
+        match started {
+            Ok(new_id) => {
+                info!(
+                    "Replaced app container of {} with {}",
+                    self.instance_id, new_id
+                );
+                self.app_container_id = Some(new_id);
+                // The swap is done, reconcile removes the old container if this fails
+                if let Err(e) = self
+                    .docker
+                    .remove_container(&old_id, Some(force_options))
+                    .await
+                {
+                    warn!(
+                        "Failed to remove the replaced app container of {}: {}",
+                        self.instance_id, e
+                    );
+                }
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Rolling back app update of {}: {}", self.instance_id, e);
+                self.env_vars = old_env;
+                self.images.app = old_image;
+                match self
+                    .docker
+                    .remove_container(&app_name, Some(force_options))
+                    .await
+                {
+                    Err(e) if !is_not_found(&e) => return Err(e.into()),
+                    _ => {}
+                }
+                self.docker
+                    .rename_container(
+                        &old_id,
+                        bollard::container::RenameContainerOptions { name: app_name },
+                    )
+                    .await?;
+                if !self.is_running(&old_id).await? {
+                    self.docker
+                        .start_container(
+                            &old_id,
+                            None::<bollard::container::StartContainerOptions<String>>,
+                        )
+                        .await?;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Docker HEALTHCHECK that only passes once MySQL answers `mysqladmin ping`
+    ///
+    /// On a fresh volume MySQL first runs a temporary server without networking, so probes
+    /// failing within the readiness timeout don't count towards marking it unhealthy.
+    fn mysql_healthcheck(&self) -> bollard::models::HealthConfig {
+        let interval = self.readiness.interval.as_nanos() as i64;
+        bollard::models::HealthConfig {
+            test: Some(vec![
+                "CMD-SHELL".to_string(),
+                "mysqladmin ping -h 127.0.0.1 -uroot -p\"$MYSQL_ROOT_PASSWORD\" --silent"
+                    .to_string(),
+            ]),
+            interval: Some(interval),
+            timeout: Some(interval),
+            retries: Some(3),
+            start_period: Some(self.readiness.timeout.as_nanos() as i64),
+            start_interval: Some(interval),
+        }
+    }
+
+    /// Docker HEALTHCHECK that only passes once the app accepts connections on [`APP_PORT`]
+    ///
+    /// The app images define no health check of their own, and run their migrations before
+    /// listening, so probes within the readiness timeout don't count against them either.
+    fn app_healthcheck(&self) -> bollard::models::HealthConfig {
+        let interval = self.readiness.interval.as_nanos() as i64;
+        let probe = format!(
+            "require('net').connect({}, '127.0.0.1')\
+             .on('connect', () => process.exit(0))\
+             .on('error', () => process.exit(1))",
+            APP_PORT
+        );
+        bollard::models::HealthConfig {
+            test: Some(vec![
+                "CMD".to_string(),
+                "node".to_string(),
+                "-e".to_string(),
+                probe,
+            ]),
+            interval: Some(interval),
+            timeout: Some(interval),
+            retries: Some(3),
+            start_period: Some(self.readiness.timeout.as_nanos() as i64),
+            start_interval: Some(interval),
+        }
     }
 
-    async fn wait_for_mysql(
+    /// Create the labelled volume serving `role`, or return the one a previous deploy left
+    async fn create_volume(&self, role: &str) -> Result<String, SimpletError> {
+        let options = bollard::volume::CreateVolumeOptions {
+            name: self.resource_name(role),
+            driver: "local".to_string(),
+            labels: self.labels(role),
+            ..Default::default()
+        };
+        // Docker returns the existing volume when one with this name exists
+        let volume = self.docker.create_volume(options).await?;
+        Ok(volume.name)
+    }
+
+    async fn create_network(&self) -> Result<String, SimpletError> {
+        let name = self.resource_name(NETWORK_ROLE);
+
+        // Reuse the network of an earlier, interrupted deploy
+        match self
+            .docker
+            .inspect_network(
+                &name,
+                None::<bollard::network::InspectNetworkOptions<String>>,
+            )
+            .await
+        {
+            Ok(network) => return Ok(network.id.unwrap_or(name)),
+            Err(e) if is_not_found(&e) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let options = bollard::network::CreateNetworkOptions {
+            name: name.clone(),
+            check_duplicate: true,
+            driver: "bridge".to_string(),
+            labels: self.labels(NETWORK_ROLE),
+            ..Default::default()
+        };
+
+        let response = self.docker.create_network(options).await?;
+        if let Some(warning) = response.warning.filter(|w| !w.is_empty()) {
+            warn!("{}", warning);
+        }
+
+        Ok(response.id.unwrap_or(name))
+    }
+
+    /// Start any of this instance's containers that are not running
+    ///
+    /// The database is brought up and probed before the app, mirroring [`Self::start`].
+    /// Returns whether anything had to be started.
+    pub async fn resume(&self) -> Result<bool, SimpletError> {
+        let mut resumed = false;
+
+        if let Some(db_id) = &self.db_container_id {
+            if !self.is_running(db_id).await? {
+                self.docker
+                    .start_container(
+                        db_id,
+                        None::<bollard::container::StartContainerOptions<String>>,
+                    )
+                    .await?;
+                self.wait_for_healthy(DB_ROLE, db_id).await?;
+                resumed = true;
+            }
+        }
+
+        if let Some(app_id) = &self.app_container_id {
+            if !self.is_running(app_id).await? {
+                self.docker
+                    .start_container(
+                        app_id,
+                        None::<bollard::container::StartContainerOptions<String>>,
+                    )
+                    .await?;
+                resumed = true;
+            }
+        }
+
+        Ok(resumed)
+    }
+
+    async fn is_running(&self, container_id: &str) -> Result<bool, SimpletError> {
+        let info = self.docker.inspect_container(container_id, None).await?;
+        Ok(info
+            .state
+            .and_then(|state| state.running)
+            .unwrap_or_default())
+    }
+
+    /// Create and start a container attached to the instance network under `alias`
+    ///
+    /// A container left behind by an interrupted deploy of this instance is started
+    /// instead of being created again, unless it was created with other settings, e.g. an
+    /// env var or published port that changed since.
+    async fn create_and_start(
         &self,
-        container: &Container<'_>,
-    ) -> Result<(), bollard::errors::Error> {
-        let max_attempts = 30;
-        let mut attempts = 0;
-
-        while attempts < max_attempts {
-            tokio::time::sleep(Duration::from_secs(2)).await;
-
-            if let Some(id) = container.id() {
-                if let Ok(info) = self.docker.inspect_container(id, None).await {
-                    if let Some(state) = info.state {
-                        if state.status == Some(bollard::secret::ContainerStateStatusEnum::RUNNING)
-                        {
-                            return Ok(());
-                        }
+        role: &str,
+        alias: &str,
+        mut config: bollard::container::Config<String>,
+    ) -> Result<String, SimpletError> {
+        use bollard::models::ContainerStateStatusEnum;
+
+        let name = self.resource_name(role);
+        let spec = spec_fingerprint(&config);
+        match self.docker.inspect_container(&name, None).await {
+            Ok(existing) if !has_label(&existing, SPEC_LABEL, &spec) => {
+                let id = existing.id.unwrap_or(name.clone());
+                info!(
+                    "Recreating container {} of {}, its settings changed",
+                    id, self.instance_id
+                );
+                let options = bollard::container::RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                };
+                self.docker.remove_container(&id, Some(options)).await?;
+            }
+            Ok(existing) => {
+                let id = existing.id.unwrap_or(name);
+                let status = existing.state.and_then(|state| state.status);
+                match status {
+                    Some(ContainerStateStatusEnum::RUNNING) => {}
+                    Some(ContainerStateStatusEnum::DEAD | ContainerStateStatusEnum::REMOVING) => {
+                        return Err(SimpletError::Unrecoverable {
+                            instance_id: self.instance_id.clone(),
+                            reason: format!("container {} is {:?}", id, status),
+                        });
+                    }
+                    _ => {
+                        info!("Resuming existing container {} of {}", id, self.instance_id);
+                        self.docker
+                            .start_container(
+                                &id,
+                                None::<bollard::container::StartContainerOptions<String>>,
+                            )
+                            .await?;
                     }
                 }
+                return Ok(id);
             }
+            Err(e) if is_not_found(&e) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let network = self.resource_name(NETWORK_ROLE);
+        let options = bollard::container::CreateContainerOptions {
+            name,
+            platform: None,
+        };
+
+        let labels = config.labels.get_or_insert_with(Default::default);
+        labels.extend(self.labels(role));
+        labels.insert(SPEC_LABEL.to_string(), spec);
+        config
+            .host_config
+            .get_or_insert_with(Default::default)
+            .network_mode = Some(network.clone());
+        config.networking_config = Some(bollard::container::NetworkingConfig {
+            endpoints_config: HashMap::from([(
+                network,
+                bollard::models::EndpointSettings {
+                    aliases: Some(vec![alias.to_string()]),
+                    ..Default::default()
+                },
+            )]),
+        });
 
-            attempts += 1;
+        let response = self.docker.create_container(Some(options), config).await?;
+        for warning in response.warnings {
+            warn!("{}", warning);
         }
 
-        Err(bollard::errors::Error::IOError {
-            err: std::io::Error::new(
-                std::io::ErrorKind::TimedOut,
-                "MySQL failed to become healthy",
-            ),
+        self.docker
+            .start_container(
+                &response.id,
+                None::<bollard::container::StartContainerOptions<String>>,
+            )
+            .await?;
+
+        Ok(response.id)
+    }
+
+    /// Wait until the container serving `role` passes its health check
+    ///
+    /// A container whose image defines no health check only needs to stay up for one
+    /// probe interval. One reported unhealthy may still recover, so it only fails once the
+    /// timeout is reached.
+    async fn wait_for_healthy(&self, role: &str, container_id: &str) -> Result<(), SimpletError> {
+        use bollard::models::{ContainerStateStatusEnum, HealthStatusEnum};
+
+        let started = Instant::now();
+        let mut unhealthy = false;
+        while started.elapsed() < self.readiness.timeout {
+            tokio::time::sleep(self.readiness.interval).await;
+
+            let state = match self.docker.inspect_container(container_id, None).await {
+                Ok(info) => info.state.unwrap_or_default(),
+                Err(e) => {
+                    warn!("Failed to inspect {} container: {}", role, e);
+                    continue;
+                }
+            };
+
+            if matches!(
+                state.status,
+                Some(ContainerStateStatusEnum::EXITED | ContainerStateStatusEnum::DEAD)
+            ) {
+                return Err(SimpletError::ContainerExited(self.resource_name(role)));
+            }
+
+            let status = state.health.and_then(|health| health.status);
+            unhealthy = status == Some(HealthStatusEnum::UNHEALTHY);
+            if let Some(HealthStatusEnum::HEALTHY) | Some(HealthStatusEnum::NONE) | None = status {
+                info!(
+                    "{} of {} ready after {:?}",
+                    role,
+                    self.instance_id,
+                    started.elapsed()
+                );
+                return Ok(());
+            }
+        }
+
+        if unhealthy {
+            return Err(SimpletError::Unhealthy(self.resource_name(role)));
+        }
+        Err(SimpletError::Timeout {
+            what: self.resource_name(role),
+            waited: self.readiness.timeout,
         })
     }
 
     fn build_app_environment(&self) -> Vec<String> {
         let mut app_env = vec![
             "APP_ENV=production".to_string(),
-            format!(
-                "APP_SECRET={}",
-                self.env_vars
-                    .get("APP_SECRET")
-                    .unwrap_or(&"secret".to_string())
-            ),
-            format!(
-                "APP_URL={}",
-                self.env_vars
-                    .get("APP_URL")
-                    .unwrap_or(&"http://localhost:3000".to_string())
-            ),
-            "API_PORT=3000".to_string(),
+            format!("APP_URL={}", self.app_url()),
+            format!("API_PORT={}", APP_PORT),
             "API_HOST=0.0.0.0".to_string(),
             format!("MYSQL_HOST={}", self.service_type.get_db_name()),
             "MYSQL_PORT=3306".to_string(),
@@ -249,6 +1291,7 @@ impl ApillonSimpletsDocker {
 
         // Add optional environment variables
         let optional_vars = [
+            "APP_SECRET",
             "ADMIN_WALLET",
             "APILLON_KEY",
             "APILLON_SECRET",
@@ -270,37 +1313,369 @@ impl ApillonSimpletsDocker {
         app_env
     }
 
-    pub async fn stop(&self) -> Result<(), bollard::errors::Error> {
-        let mut app_container = Container::new(&self.docker, self.service_type.get_app_image());
-        let mut db_container = Container::new(&self.docker, "mysql");
-
-        app_container.stop().await?;
-        db_container.stop().await?;
+    pub async fn stop(&self) -> Result<(), SimpletError> {
+        // Stop the app before its database
+        for id in [&self.app_container_id, &self.db_container_id]
+            .into_iter()
+            .flatten()
+        {
+            self.docker
+                .stop_container(id, None::<bollard::container::StopContainerOptions>)
+                .await?;
+        }
         Ok(())
     }
 
-    pub async fn cleanup(self) -> Result<(), bollard::errors::Error> {
+    /// Remove the containers and network of the instance, and its volumes if `volumes`
+    /// is [`VolumePolicy::Purge`]
+    pub async fn cleanup(self, volumes: VolumePolicy) -> Result<(), SimpletError> {
         let force_options = bollard::container::RemoveContainerOptions {
             force: true,
             ..Default::default()
         };
 
-        let app_container = Container::new(&self.docker, self.service_type.get_app_image());
-        let db_container = Container::new(&self.docker, "mysql");
+        // A container that is already gone must not keep the instance from being stopped
+        for id in [&self.app_container_id, &self.db_container_id]
+            .into_iter()
+            .flatten()
+        {
+            match self.docker.remove_container(id, Some(force_options)).await {
+                Err(e) if !is_not_found(&e) => return Err(e.into()),
+                _ => {}
+            }
+        }
 
-        app_container.remove(Some(force_options)).await?;
-        db_container.remove(Some(force_options)).await?;
+        if let Some(network_id) = &self.network_id {
+            // The network can't be removed while the proxy is still attached to it
+            if self.endpoint.as_ref().is_some_and(|e| e.hostname.is_some()) {
+                let network = self.resource_name(NETWORK_ROLE);
+                proxy::detach(&self.docker, self.service_id, &network).await?;
+            }
+            match self.docker.remove_network(network_id).await {
+                Err(e) if !is_not_found(&e) => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        if volumes == VolumePolicy::Purge {
+            for name in self.volume_names() {
+                match self
+                    .docker
+                    .remove_volume(&name, None::<bollard::volume::RemoveVolumeOptions>)
+                    .await
+                {
+                    Err(e) if !is_not_found(&e) => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
         Ok(())
     }
+
+    /// Names of the data volumes of the instance
+    pub fn volume_names(&self) -> Vec<String> {
+        [DB_VOLUME_ROLE, APP_VOLUME_ROLE]
+            .into_iter()
+            .map(|role| self.resource_name(role))
+            .collect()
+    }
+}
+
+/// Fingerprint of the settings a container is created with
+fn spec_fingerprint(config: &bollard::container::Config<String>) -> String {
+    // Going through a `Value` sorts the keys of the maps in the config
+    let serialized = serde_json::to_value(config).unwrap().to_string();
+    to_hex(&keccak_256(serialized.as_bytes())[..], false)
+}
+
+/// Whether `container` carries `label` with `value`
+fn has_label(
+    container: &bollard::models::ContainerInspectResponse,
+    label: &str,
+    value: &str,
+) -> bool {
+    container
+        .config
+        .as_ref()
+        .and_then(|config| config.labels.as_ref())
+        .and_then(|labels| labels.get(label))
+        .is_some_and(|current| current == value)
+}
+
+/// Whether a Docker API call failed because the resource does not exist
+pub(crate) fn is_not_found(error: &bollard::errors::Error) -> bool {
+    matches!(
+        error,
+        bollard::errors::Error::DockerResponseServerError {
+            status_code: 404,
+            ..
+        }
+    )
 }
 
 pub async fn deploy_service<T: ServiceConfig>(
     config: T,
     service_type: ServiceType,
-) -> Result<ApillonSimpletsDocker, bollard::errors::Error> {
+    identity: &InstanceIdentity,
+    options: &DeployOptions,
+) -> Result<ApillonSimpletsDocker, SimpletError> {
+    config.validate()?;
+
+    let instance_id = service_type.instance_id(&identity.hash());
     let env_vars = config.into_env_vars();
     let docker = connect_to_docker(None).await?;
-    let simplets = ApillonSimpletsDocker::new(docker, env_vars, service_type);
+    let images = options
+        .images
+        .clone()
+        .unwrap_or_else(|| ImageRefs::defaults(service_type));
+    let mut simplets = ApillonSimpletsDocker::new(docker, instance_id, env_vars, service_type)
+        .with_service_id(identity.service_id)
+        .with_images(images)
+        .with_registries(options.registries.clone())
+        .with_readiness(options.readiness);
+    if let Some(endpoint) = &options.endpoint {
+        simplets = simplets.with_endpoint(endpoint.clone());
+    }
+    if let Some(limits) = &options.limits {
+        simplets = simplets.with_limits(limits.clone());
+    }
     simplets.start().await?;
     Ok(simplets)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{fake_docker, Route};
+    use super::*;
+
+    fn config(value: &str) -> CommonConfig {
+        CommonConfig {
+            name: None,
+            custom_domain: None,
+            tier: None,
+            app_secret: Some(format!("{value}-secret")),
+            app_url: Some(format!("http://{value}")),
+            mysql_password: Some(format!("{value}-password")),
+            mysql_db: Some(value.to_string()),
+            admin_wallet: Some(format!("{value}-wallet")),
+            apillon_key: None,
+            apillon_secret: None,
+            smtp_config: None,
+        }
+    }
+
+    #[test]
+    fn test_merge_applies_field_policies() {
+        let operator = CommonConfig {
+            apillon_key: Some("operator-key".to_string()),
+            ..config("operator")
+        };
+        let merged = CommonConfig::merge(&operator, &config("caller"));
+
+        // Caller overridable
+        assert_eq!(merged.apillon_key.as_deref(), Some("operator-key"));
+        // Operator locked
+        assert_eq!(merged.mysql_password.as_deref(), Some("operator-password"));
+        // Caller only
+        assert_eq!(merged.admin_wallet.as_deref(), Some("caller-wallet"));
+        assert_eq!(merged.app_url.as_deref(), Some("http://caller"));
+        assert_eq!(merged.app_secret.as_deref(), Some("caller-secret"));
+
+        // Every instance derives its own URL and secret unless its caller sets them
+        let merged = CommonConfig::merge(&operator, &CommonConfig::default());
+        assert!(merged.admin_wallet.is_none());
+        assert!(merged.app_url.is_none());
+        assert!(merged.app_secret.is_none());
+        assert_ne!(random_app_secret(), random_app_secret());
+    }
+
+    #[test]
+    fn test_redacted_hides_secrets() {
+        let redacted = config("caller").redacted();
+        let logged = format!("{:?}", redacted);
+
+        assert!(!logged.contains("caller-secret"));
+        assert!(!logged.contains("caller-password"));
+        assert_eq!(redacted.app_url.as_deref(), Some("http://caller"));
+    }
+
+    #[test]
+    fn test_partial_config_rejects_locked_fields() {
+        let update = partial_update::<proof_of_attendance::ProofOfAttendanceConfig>(
+            br#"{"app_url": "https://event.example.com"}"#,
+        )
+        .unwrap();
+        assert_eq!(update.env_vars.len(), 1);
+        assert_eq!(update.env_vars["APP_URL"], "https://event.example.com");
+        assert!(update.custom_domain.is_none());
+
+        let locked = partial_update::<proof_of_attendance::ProofOfAttendanceConfig>(
+            br#"{"mysql_password": "hunter2"}"#,
+        );
+        assert!(matches!(locked, Err(SimpletError::InvalidConfig(_))));
+
+        // Neither would change anything about the running instance
+        for update in [&br#"{"name": "launch"}"#[..], br#"{"tier": "large"}"#] {
+            let Err(SimpletError::InvalidConfig(reason)) =
+                partial_update::<proof_of_attendance::ProofOfAttendanceConfig>(update)
+            else {
+                panic!("deploy-only field was accepted");
+            };
+            assert!(reason.contains("cannot be updated"), "{}", reason);
+        }
+
+        let update = partial_update::<proof_of_attendance::ProofOfAttendanceConfig>(
+            br#"{"custom_domain": "POAP.myconf.xyz"}"#,
+        )
+        .unwrap();
+        assert!(update.env_vars.is_empty());
+        assert_eq!(update.custom_domain.as_deref(), Some("poap.myconf.xyz"));
+
+        // The SMTP sender can change without resending the relay's credentials
+        let update = partial_update::<proof_of_attendance::ProofOfAttendanceConfig>(
+            br#"{"smtp_config": {"name_from": "Example Conf"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            update.env_vars,
+            HashMap::from([("SMTP_NAME_FROM".to_string(), "Example Conf".to_string())])
+        );
+
+        let unknown = partial_update::<proof_of_attendance::ProofOfAttendanceConfig>(
+            br#"{"smtp_config": {"sender": "Example Conf"}}"#,
+        );
+        assert!(matches!(unknown, Err(SimpletError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_retag_image() {
+        assert_eq!(retag_image("ps-poa:latest", "1.4.2"), "ps-poa:1.4.2");
+        assert_eq!(
+            retag_image("localhost:5000/apillon/ps-poa@sha256:abc", "1.4.2"),
+            "localhost:5000/apillon/ps-poa:1.4.2"
+        );
+        assert_eq!(retag_image("ps-poa", "sha256:def"), "ps-poa@sha256:def");
+    }
+
+    #[test]
+    fn test_instance_id_ignores_config() {
+        let identity = |owner: &str, name: Option<&str>| InstanceIdentity {
+            service_id: 1,
+            owner: owner.to_string(),
+            name: name.map(str::to_string),
+        };
+
+        // Each owner's unnamed instance and named ones are all distinct
+        assert_ne!(identity("a", None).hash(), identity("b", None).hash());
+        assert_ne!(
+            identity("a", None).hash(),
+            identity("a", Some("launch")).hash()
+        );
+        assert_ne!(
+            identity("a", Some("launch")).hash(),
+            identity("a", Some("other")).hash()
+        );
+        assert_ne!(
+            identity("a", Some("launch")).hash(),
+            identity("b", Some("launch")).hash()
+        );
+
+        // Changing a secret changes the config an instance runs with, not which instance it is
+        let deployed = |smtp_password: &str| {
+            let common = CommonConfig {
+                name: Some("launch".to_string()),
+                smtp_config: Some(SmtpConfig {
+                    host: "smtp.example.com".to_string(),
+                    port: "587".to_string(),
+                    username: "relay".to_string(),
+                    password: smtp_password.to_string(),
+                    email_from: "events@example.com".to_string(),
+                    name_from: "Events".to_string(),
+                }),
+                ..config("caller")
+            };
+            let identity = identity("a", common.name.as_deref());
+            (
+                ServiceType::ProofOfAttendance.instance_id(&identity.hash()),
+                env_fingerprint(&common.build_env_vars()),
+            )
+        };
+        let (first_id, first_fingerprint) = deployed("first");
+        let (second_id, second_fingerprint) = deployed("second");
+        assert_eq!(first_id, second_id);
+        assert_ne!(first_fingerprint, second_fingerprint);
+    }
+
+    #[tokio::test]
+    async fn test_healthchecks_allow_slow_starts() {
+        let readiness = ReadinessConfig {
+            timeout: Duration::from_secs(300),
+            interval: Duration::from_secs(5),
+        };
+        let service = ApillonSimpletsDocker::new(
+            Arc::new(bollard::Docker::connect_with_http_defaults().unwrap()),
+            "poa-test",
+            HashMap::new(),
+            ServiceType::ProofOfAttendance,
+        )
+        .with_readiness(readiness);
+
+        for healthcheck in [service.mysql_healthcheck(), service.app_healthcheck()] {
+            assert_eq!(healthcheck.start_period, Some(300_000_000_000));
+            assert_eq!(healthcheck.interval, Some(5_000_000_000));
+        }
+        let probe = service.app_healthcheck().test.unwrap().join(" ");
+        assert!(probe.contains(&APP_PORT.to_string()));
+    }
+
+    #[test]
+    fn test_spec_fingerprint_tracks_settings() {
+        let config = |env: &str, labels: &[(&str, &str)]| bollard::container::Config {
+            env: Some(vec![env.to_string()]),
+            labels: Some(
+                labels
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+
+        // Maps are compared by content, whatever order they iterate in
+        assert_eq!(
+            spec_fingerprint(&config("A=1", &[("a", "1"), ("b", "2")])),
+            spec_fingerprint(&config("A=1", &[("b", "2"), ("a", "1")]))
+        );
+        assert_ne!(
+            spec_fingerprint(&config("A=1", &[])),
+            spec_fingerprint(&config("A=2", &[]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_tolerates_removed_container() {
+        let (docker, requests) = fake_docker(vec![Route::not_found("/containers/gone-app")]).await;
+        let mut service = ApillonSimpletsDocker::new(
+            Arc::new(docker),
+            "poa-test",
+            HashMap::new(),
+            ServiceType::ProofOfAttendance,
+        );
+        service.app_container_id = Some("gone-app".to_string());
+        service.db_container_id = Some("db".to_string());
+        service.network_id = Some("net".to_string());
+
+        service.cleanup(VolumePolicy::Retain).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert!(requests
+            .iter()
+            .any(|r| r.starts_with("DELETE /containers/gone-app")));
+        assert!(requests
+            .iter()
+            .any(|r| r.starts_with("DELETE /containers/db")));
+        assert!(requests
+            .iter()
+            .any(|r| r.starts_with("DELETE /networks/net")));
+    }
+}
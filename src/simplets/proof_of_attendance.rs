@@ -1,17 +1,26 @@
 use super::{
-    deploy_service, ApillonSimpletsDocker, CommonConfig, ServiceConfig, ServiceType,
-    SimpletsBuilder, SmtpConfig,
+    deploy_service, ApillonSimpletsDocker, CommonConfig, DeployOptions, InstanceIdentity,
+    ServiceConfig, ServiceType, SimpletsBuilder, SmtpConfig,
 };
-use gadget_sdk::docker::bollard;
+use crate::error::SimpletError;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ProofOfAttendanceConfig {
-    common: CommonConfig,
+    #[serde(flatten)]
+    pub common: CommonConfig,
 }
 
 impl ServiceConfig for ProofOfAttendanceConfig {
+    fn common(&self) -> &CommonConfig {
+        &self.common
+    }
+
+    fn common_mut(&mut self) -> &mut CommonConfig {
+        &mut self.common
+    }
+
     fn into_env_vars(self) -> HashMap<String, String> {
         self.common.build_env_vars()
     }
@@ -25,10 +34,15 @@ pub struct ProofOfAttendanceBuilder {
 impl SimpletsBuilder for ProofOfAttendanceBuilder {
     type Config = ProofOfAttendanceConfig;
 
+    const SERVICE_TYPE: ServiceType = ServiceType::ProofOfAttendance;
+
     fn new() -> Self {
         Self {
             config: ProofOfAttendanceConfig {
                 common: CommonConfig {
+                    name: None,
+                    custom_domain: None,
+                    tier: None,
                     app_secret: None,
                     app_url: None,
                     mysql_password: None,
@@ -42,6 +56,10 @@ impl SimpletsBuilder for ProofOfAttendanceBuilder {
         }
     }
 
+    fn from_config(config: Self::Config) -> Self {
+        Self { config }
+    }
+
     fn get_config(&self) -> &Self::Config {
         &self.config
     }
@@ -86,8 +104,12 @@ impl SimpletsBuilder for ProofOfAttendanceBuilder {
         self
     }
 
-    async fn deploy(self) -> Result<ApillonSimpletsDocker, bollard::errors::Error> {
-        deploy_service(self.config, ServiceType::ProofOfAttendance).await
+    async fn deploy(
+        self,
+        identity: &InstanceIdentity,
+        options: &DeployOptions,
+    ) -> Result<ApillonSimpletsDocker, SimpletError> {
+        deploy_service(self.config, Self::SERVICE_TYPE, identity, options).await
     }
 }
 
@@ -112,7 +134,14 @@ mod tests {
                 email_from: "test@test.com".to_string(),
                 name_from: "Test Sender".to_string(),
             })
-            .deploy()
+            .deploy(
+                &InstanceIdentity {
+                    service_id: 0,
+                    owner: "test".to_string(),
+                    name: None,
+                },
+                &DeployOptions::default(),
+            )
             .await;
 
         assert!(poa.is_ok());
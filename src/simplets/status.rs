@@ -0,0 +1,192 @@
+use super::{is_not_found, ApillonSimpletsDocker, ServiceType, APP_ROLE, DB_ROLE};
+use crate::certificates::CertificateInfo;
+use crate::error::SimpletError;
+use gadget_sdk::docker::bollard::models::{ContainerStateStatusEnum, HealthStatusEnum};
+use serde::{Deserialize, Serialize};
+
+/// Overall state of a simplet instance
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstancePhase {
+    /// Containers are running but the database is not healthy yet
+    Starting,
+    /// Every container is running and healthy
+    Healthy,
+    /// Some containers are down, restarting or unhealthy
+    Degraded,
+    /// No container is running
+    Stopped,
+}
+
+/// Live state of one container of an instance
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ContainerStatus {
+    pub role: String,
+    pub id: Option<String>,
+    /// Docker state such as `running` or `exited`, `None` if the container is gone
+    pub state: Option<ContainerStateStatusEnum>,
+    /// Healthcheck status, if the container has one
+    pub health: Option<HealthStatusEnum>,
+    pub restart_count: i64,
+    pub uptime_secs: Option<u64>,
+    pub image: Option<String>,
+    /// Repo digest of the image, or its id for locally built images
+    pub image_digest: Option<String>,
+}
+
+impl ContainerStatus {
+    fn is_running(&self) -> bool {
+        self.state == Some(ContainerStateStatusEnum::RUNNING)
+    }
+}
+
+/// Result of the status query job
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InstanceStatus {
+    pub instance_id: String,
+    pub service_type: ServiceType,
+    pub phase: InstancePhase,
+    pub url: String,
+    pub containers: Vec<ContainerStatus>,
+    /// Certificates served for the app's host names, filled in from the registry
+    pub certificates: Vec<CertificateInfo>,
+}
+
+impl InstancePhase {
+    fn from_containers(containers: &[ContainerStatus]) -> Self {
+        let running = containers.iter().filter(|c| c.is_running()).count();
+        if running == 0 {
+            return InstancePhase::Stopped;
+        }
+        if running < containers.len() {
+            return InstancePhase::Degraded;
+        }
+
+        let healths = containers
+            .iter()
+            .filter_map(|c| c.health)
+            .collect::<Vec<_>>();
+        if healths.contains(&HealthStatusEnum::UNHEALTHY) {
+            InstancePhase::Degraded
+        } else if healths.contains(&HealthStatusEnum::STARTING) {
+            InstancePhase::Starting
+        } else {
+            InstancePhase::Healthy
+        }
+    }
+}
+
+impl ApillonSimpletsDocker {
+    /// Inspect the containers of this instance
+    pub async fn status(&self) -> Result<InstanceStatus, SimpletError> {
+        let mut containers = Vec::new();
+        for (role, id) in [
+            (DB_ROLE, &self.db_container_id),
+            (APP_ROLE, &self.app_container_id),
+        ] {
+            containers.push(self.container_status(role, id.as_deref()).await?);
+        }
+
+        Ok(InstanceStatus {
+            instance_id: self.instance_id.clone(),
+            service_type: self.service_type,
+            phase: InstancePhase::from_containers(&containers),
+            url: self.app_url(),
+            containers,
+            certificates: Vec::new(),
+        })
+    }
+
+    async fn container_status(
+        &self,
+        role: &str,
+        id: Option<&str>,
+    ) -> Result<ContainerStatus, SimpletError> {
+        let mut status = ContainerStatus {
+            role: role.to_string(),
+            id: id.map(ToString::to_string),
+            state: None,
+            health: None,
+            restart_count: 0,
+            uptime_secs: None,
+            image: None,
+            image_digest: None,
+        };
+
+        let Some(id) = id else {
+            return Ok(status);
+        };
+        let info = match self.docker.inspect_container(id, None).await {
+            Ok(info) => info,
+            // The container was removed behind our back
+            Err(e) if is_not_found(&e) => return Ok(status),
+            Err(e) => return Err(e.into()),
+        };
+
+        let state = info.state.unwrap_or_default();
+        status.state = state.status;
+        status.health = state.health.and_then(|health| health.status);
+        status.restart_count = info.restart_count.unwrap_or_default();
+        if state.status == Some(ContainerStateStatusEnum::RUNNING) {
+            status.uptime_secs = state
+                .started_at
+                .and_then(|started| chrono::DateTime::parse_from_rfc3339(&started).ok())
+                .map(|started| (chrono::Utc::now() - started.to_utc()).num_seconds().max(0) as u64);
+        }
+        status.image = info.config.and_then(|config| config.image);
+
+        if let Some(image_id) = info.image {
+            let digest = self
+                .docker
+                .inspect_image(&image_id)
+                .await
+                .ok()
+                .and_then(|image| image.repo_digests)
+                .and_then(|digests| digests.into_iter().next());
+            status.image_digest = Some(digest.unwrap_or(image_id));
+        }
+
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(
+        state: ContainerStateStatusEnum,
+        health: Option<HealthStatusEnum>,
+    ) -> ContainerStatus {
+        ContainerStatus {
+            role: DB_ROLE.to_string(),
+            id: Some("id".to_string()),
+            state: Some(state),
+            health,
+            restart_count: 0,
+            uptime_secs: None,
+            image: None,
+            image_digest: None,
+        }
+    }
+
+    #[test]
+    fn test_phase_from_containers() {
+        let phase = |containers: &[ContainerStatus]| InstancePhase::from_containers(containers);
+
+        use ContainerStateStatusEnum::{EXITED, RUNNING};
+        use HealthStatusEnum::{HEALTHY, STARTING};
+
+        let healthy = [container(RUNNING, Some(HEALTHY)), container(RUNNING, None)];
+        assert_eq!(phase(&healthy), InstancePhase::Healthy);
+
+        let starting = [container(RUNNING, Some(STARTING)), container(RUNNING, None)];
+        assert_eq!(phase(&starting), InstancePhase::Starting);
+
+        let degraded = [container(RUNNING, Some(HEALTHY)), container(EXITED, None)];
+        assert_eq!(phase(&degraded), InstancePhase::Degraded);
+
+        let stopped = [container(EXITED, None), container(EXITED, None)];
+        assert_eq!(phase(&stopped), InstancePhase::Stopped);
+    }
+}
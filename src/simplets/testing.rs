@@ -0,0 +1,72 @@
+use gadget_sdk::docker::bollard;
+use std::sync::{Arc, Mutex};
+
+/// Answer of [`fake_docker`] to the requests whose request line contains `pattern`
+pub struct Route {
+    pub pattern: &'static str,
+    pub status: u16,
+    pub body: serde_json::Value,
+}
+
+impl Route {
+    pub fn new(pattern: &'static str, status: u16, body: serde_json::Value) -> Self {
+        Self {
+            pattern,
+            status,
+            body,
+        }
+    }
+
+    /// Answer `pattern` with a 404, as Docker does for missing resources
+    pub fn not_found(pattern: &'static str) -> Self {
+        Self::new(
+            pattern,
+            404,
+            serde_json::json!({ "message": "No such container" }),
+        )
+    }
+}
+
+/// Serve Docker API requests with the first of `routes` matching them, and with an empty
+/// `204 No Content` if none does
+///
+/// Returns a client for the server, and the request lines it received.
+pub async fn fake_docker(routes: Vec<Route>) -> (bollard::Docker, Arc<Mutex<Vec<String>>>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buf = vec![0; 4096];
+            let len = stream.read(&mut buf).await.unwrap_or_default();
+            let request = String::from_utf8_lossy(&buf[..len]);
+            let line = request.lines().next().unwrap_or_default().to_string();
+            let response = match routes.iter().find(|route| line.contains(route.pattern)) {
+                Some(route) => {
+                    let body = route.body.to_string();
+                    format!(
+                        "HTTP/1.1 {} Fake\r\nContent-Type: application/json\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        route.status,
+                        body.len(),
+                        body
+                    )
+                }
+                None => "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n".to_string(),
+            };
+            seen.lock().unwrap().push(line);
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+
+    let docker = bollard::Docker::connect_with_http(
+        &format!("http://{}", addr),
+        5,
+        bollard::API_DEFAULT_VERSION,
+    )
+    .unwrap();
+    (docker, requests)
+}